@@ -0,0 +1,22 @@
+extern crate cow_structs;
+
+mod common;
+
+use cow_structs::CowVec;
+use common::bench;
+
+const LEN: usize = 100_000;
+const RUNS: u32 = 100;
+
+fn build<F: FnMut(&mut CowVec<usize>)>(mut f: F) {
+    let mut v = CowVec::new();
+    f(&mut v);
+    assert!(v.len() == LEN);
+}
+
+fn main() {
+    bench("append_iter, exact size", RUNS, || build(|v| v.append_iter(0..LEN)));
+    bench("extend, exact size", RUNS, || build(|v| v.extend(0..LEN)));
+    // the size hint is not exact so the root is grown on demand
+    bench("append_iter, filtered", RUNS, || build(|v| v.append_iter((0..LEN).filter(|_| true))));
+}
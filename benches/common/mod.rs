@@ -0,0 +1,10 @@
+use std::time::Instant;
+
+// runs `f` the given number of times and prints the mean duration
+pub fn bench<F: FnMut()>(name: &str, runs: u32, mut f: F) {
+    let start = Instant::now();
+    for _ in 0..runs {
+        f();
+    }
+    println!("{}: {:?} per run", name, start.elapsed() / runs);
+}
@@ -0,0 +1,19 @@
+extern crate cow_structs;
+
+mod common;
+
+use cow_structs::CowVec;
+use common::bench;
+
+const LEN: usize = 1_000_000;
+const RUNS: u32 = 20;
+
+fn main() {
+    // built separately so that no leaf is shared
+    let a: CowVec<u64> = (0..LEN as u64).collect();
+    let b: CowVec<u64> = (0..LEN as u64).collect();
+    bench("element-wise eq", RUNS, || assert!(a.iter().eq(b.iter())));
+    bench("leaf eq", RUNS, || assert!(a == b));
+    let c = a.clone();
+    bench("leaf eq, shared", RUNS, || assert!(a == c));
+}
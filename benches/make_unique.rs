@@ -0,0 +1,37 @@
+extern crate cow_structs;
+
+mod common;
+
+use cow_structs::CowVec;
+
+const LEN: usize = 1 << 20;
+const RUNS: u32 = 10;
+
+// clones the vector then writes to every element
+fn bench<F: FnMut(&mut CowVec<usize>)>(name: &str, mut f: F) {
+    let original: CowVec<usize> = (0..LEN).collect();
+    common::bench(name, RUNS, || {
+        let mut v = original.clone();
+        f(&mut v);
+        assert!(v.len() == LEN);
+    });
+}
+
+fn main() {
+    bench("get_mut", |v| {
+        for i in 0..LEN {
+            *v.get_mut(i) += 1;
+        }
+    });
+    bench("make_unique then get_mut", |v| {
+        let v = v.make_unique();
+        for i in 0..LEN {
+            *v.get_mut(i) += 1;
+        }
+    });
+    bench("iter_mut", |v| {
+        for x in v.iter_mut() {
+            *x += 1;
+        }
+    });
+}
@@ -0,0 +1,35 @@
+extern crate cow_structs;
+
+mod common;
+
+use std::mem;
+use cow_structs::CowVec;
+
+const LEN: usize = 1 << 20;
+const RUNS: u32 = 10;
+
+fn naive_reverse(v: &mut CowVec<usize>) {
+    let len = v.len();
+    for i in 0..len / 2 {
+        let a = *v.get(i);
+        let b = mem::replace(v.get_mut(len - 1 - i), a);
+        *v.get_mut(i) = b;
+    }
+}
+
+// reverses the same vector on every run
+fn bench<F: FnMut(&mut CowVec<usize>)>(name: &str, mut f: F) {
+    let mut v: CowVec<usize> = (0..LEN).collect();
+    common::bench(name, RUNS, || f(&mut v));
+    assert!(v.len() == LEN);
+}
+
+fn main() {
+    bench("naive reverse", naive_reverse);
+    bench("leaf reverse", CowVec::reverse);
+    // keeps a shared version alive so every leaf is copied
+    bench("leaf reverse, shared", |v| {
+        let _old = v.clone();
+        v.reverse();
+    });
+}
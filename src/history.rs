@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use vec::{CowVec, Snapshot};
+
+// Retains the last versions of a vector,
+// the unchanged nodes are shared between them.
+#[derive(Debug, Clone)]
+pub struct History<V> {
+    versions: VecDeque<Snapshot<V>>,
+    capacity: usize,
+}
+
+impl<V: Clone> History<V> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "history capacity must be positive");
+        History {
+            versions: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    pub fn commit(&mut self, v: CowVec<V>) {
+        if self.versions.len() == self.capacity {
+            self.versions.pop_front();
+        }
+        self.versions.push_back(v.snapshot());
+    }
+
+    // `get(0)` is the latest version
+    pub fn get(&self, version_back: usize) -> Option<&Snapshot<V>> {
+        let len = self.versions.len();
+        if version_back < len {
+            self.versions.get(len - 1 - version_back)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use vec::NODE_SIZE;
+
+    #[test]
+    fn commit_get() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let mut history = History::new(3);
+
+        for i in 0..5 {
+            *v.get_mut(i * NODE_SIZE / 2) = n;
+            history.commit(v.clone());
+        }
+        assert!(history.len() == 3);
+        assert!(history.get(3).is_none());
+
+        for back in 0..3 {
+            let version = history.get(back).unwrap();
+            let changed = 5 - back;
+            for i in 0..n {
+                let expected = if i % (NODE_SIZE / 2) == 0 && i / (NODE_SIZE / 2) < changed {
+                    n
+                } else {
+                    i
+                };
+                assert!(version.get(i) == &expected);
+            }
+        }
+
+        // the first leaf and the tail are untouched by the last commits
+        let latest = history.get(0).unwrap();
+        let oldest = history.get(2).unwrap();
+        assert!(latest.shared_node_count(oldest) == 2);
+        assert!(latest.shared_node_count(history.get(1).unwrap()) == 3);
+    }
+}
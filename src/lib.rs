@@ -1,5 +1,10 @@
 extern crate arrayvec;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
 pub mod vec;
+pub mod history;
 
 pub use self::vec::CowVec;
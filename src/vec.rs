@@ -1,5 +1,12 @@
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::mem;
+use std::iter::{self, FromIterator, FusedIterator};
+use std::io;
+use std::ops::Deref;
+use std::hash::{Hash, Hasher};
+use std::cmp::Ordering;
+use std::ops::{Add, Bound, Range, RangeBounds};
+use std::collections::{HashMap, HashSet, VecDeque};
 use arrayvec::ArrayVec;
 
 pub const NODE_SIZE: usize = 32;
@@ -13,8 +20,8 @@ enum Node<V> {
     Empty,
 }
 
-type InternalNode<V> = ArrayVec<[Node<V>; NODE_SIZE]>;
-type ExternalNode<V> = ArrayVec<[V; NODE_SIZE]>;
+type InternalNode<V> = ArrayVec<Node<V>, NODE_SIZE>;
+type ExternalNode<V> = ArrayVec<V, NODE_SIZE>;
 
 #[derive(Debug, Clone)]
 pub struct CowVec<V> {
@@ -38,6 +45,35 @@ impl<V: Clone> CowVec<V> {
         self.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // moves the elements into new leaves, without cloning them
+    pub fn from_vec(values: Vec<V>) -> Self {
+        let mut res = CowVec::new();
+        res.extend_batched(values.into_iter());
+        res
+    }
+
+    pub fn from_fn<F: FnMut(usize) -> V>(n: usize, f: F) -> Self {
+        let mut res = CowVec::new();
+        res.extend_batched((0..n).map(f));
+        res
+    }
+
+    pub fn depth_for_len(len: usize) -> usize {
+        // the tail holds the last 1 to NODE_SIZE elements
+        let tree_leaves = len.saturating_sub(1) / NODE_SIZE;
+        let mut depth = 0;
+        let mut capacity = 1;
+        while capacity < tree_leaves {
+            capacity *= NODE_SIZE;
+            depth += 1;
+        }
+        depth
+    }
+
     pub fn push(&mut self, value: V) {
         if self.tail.len() < NODE_SIZE {
             Arc::make_mut(&mut self.tail).push(value);
@@ -45,20 +81,45 @@ impl<V: Clone> CowVec<V> {
             return;
         }
 
-        let new_tail = new_external_node(value);
-        let old_tail = Node::External(mem::replace(&mut self.tail, new_tail));
+        self.push_leaf(new_external_node(value));
+    }
+
+    // pushes a whole leaf which becomes the new tail,
+    // the current tail must be full unless the vector is empty.
+    fn push_leaf(&mut self, leaf: Arc<ExternalNode<V>>) {
+        let leaf_len = leaf.len();
+        if self.len == 0 {
+            self.tail = leaf;
+            self.len = leaf_len;
+            return;
+        }
+
+        debug_assert!(self.tail.len() == NODE_SIZE);
+        let old_tail = Node::External(mem::replace(&mut self.tail, leaf));
+        self.push_tail_node(old_tail);
+        self.len += leaf_len;
+    }
 
+    fn push_tail_node(&mut self, old_tail: Node<V>) {
         // special case where the tail becomes the root
         if self.len == NODE_SIZE {
-            self.root = old_tail;
-            self.len += 1;
+            if self.depth == 0 {
+                self.root = old_tail;
+            } else {
+                // the depth was ensured beforehand
+                self.root = new_internal_node();
+                Self::new_path(self.root.make_internal_mut(), self.depth, old_tail);
+            }
             return;
         }
 
-        if let &mut Node::Internal(ref mut r) = &mut self.root {
-            if r.len() < NODE_SIZE {
-                Self::push_external(Arc::make_mut(r), self.depth, old_tail);
-                self.len += 1;
+        // index of the first element of the pushed leaf
+        let offset = self.len - NODE_SIZE;
+        let capacity = 1usize.checked_shl((self.depth * SHIFT) as u32);
+        let root_full = capacity == Some(offset >> SHIFT);
+        if let Node::Internal(ref mut r) = self.root {
+            if !root_full {
+                Self::push_external(Arc::make_mut(r), self.depth, offset, old_tail);
                 return;
             }
         }
@@ -68,15 +129,68 @@ impl<V: Clone> CowVec<V> {
         new_root.push(old_root);
         self.depth += 1;
         Self::new_path(new_root, self.depth, old_tail);
-        self.len += 1;
     }
 
-    fn push_external(node: &mut InternalNode<V>, depth: usize, ext: Node<V>) {
+    // fills the tail then pushes whole leaves
+    fn extend_batched<I: Iterator<Item = V>>(&mut self, mut iter: I) {
+        while self.tail.len() < NODE_SIZE {
+            match iter.next() {
+                Some(value) => self.push(value),
+                None => return,
+            }
+        }
+
+        loop {
+            let leaf: ExternalNode<V> = iter.by_ref().take(NODE_SIZE).collect();
+            if leaf.is_empty() { return; }
+            self.push_leaf(Arc::new(leaf));
+        }
+    }
+
+    // an iterator knowing its exact length grows the root once beforehand
+    pub fn append_iter<I: IntoIterator<Item = V>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        if let (lower, Some(upper)) = iter.size_hint() {
+            if lower == upper {
+                let depth = Self::depth_for_len(self.len.saturating_add(lower));
+                self.ensure_depth(depth);
+            }
+        }
+        self.extend_batched(iter);
+    }
+
+    pub fn append(&mut self, other: &mut CowVec<V>) {
+        let other = mem::take(other);
+        self.extend_from_cow(other);
+    }
+
+    pub fn prepend(&mut self, other: &mut CowVec<V>) {
+        let mut res = mem::take(other);
+        res.append(self);
+        *self = res;
+    }
+
+    // pushes the elements of `other`, its leaves are shared if the tail is full.
+    // otherwise they are moved out of the unique leaves.
+    fn extend_from_cow(&mut self, other: CowVec<V>) {
+        if self.len != 0 && self.tail.len() < NODE_SIZE {
+            return self.extend(other);
+        }
+
+        if other.is_empty() { return; }
+        other.for_each_leaf(&mut |leaf| self.push_leaf(leaf.clone()));
+    }
+
+    fn push_external(node: &mut InternalNode<V>,
+                     depth: usize,
+                     offset: usize,
+                     ext: Node<V>) {
         if depth == 1 {
             node.push(ext);
         } else {
-            if let Some(n) = node.last_mut() {
-                return Self::push_external(n.make_internal_mut(), depth - 1, ext);
+            let sub_index = (offset >> (depth * SHIFT)) & MASK;
+            if let Some(n) = node.get_mut(sub_index) {
+                return Self::push_external(n.make_internal_mut(), depth - 1, offset, ext);
             }
 
             Self::new_path(node, depth, ext);
@@ -96,26 +210,29 @@ impl<V: Clone> CowVec<V> {
 
     pub fn pop(&mut self) -> Option<V> {
         if self.len == 0 { return None; }
-        if self.len == 1 || self.tail.len() > 1 {
-            self.len -= 1;
-            return Arc::make_mut(&mut self.tail).pop();
+        // moved out when the tail is unique
+        let value = Arc::make_mut(&mut self.tail).pop();
+        self.len -= 1;
+        if self.tail.is_empty() && self.len > 0 {
+            self.pull_tail_leaf();
         }
+        value
+    }
 
-        // special case where the root becomes the tail
-        if (self.len - 1) == NODE_SIZE {
+    // moves the last leaf of the tree to the empty tail
+    fn pull_tail_leaf(&mut self) {
+        debug_assert!(self.tail.is_empty() && self.len > 0);
+        // special case where the only leaf of the tree becomes the tail
+        if self.len == NODE_SIZE {
+            let leaf = self.leaf_arc(0).clone();
+            self.root = Node::Empty;
+            self.tail = leaf;
             self.depth = 0;
-            self.len -= 1;
-            // TODO: clone one or make mut all ?
-            let value = self.tail.last().unwrap().clone();
-            self.tail = mem::replace(&mut self.root, Node::Empty).to_external();
-            return Some(value);
+            return;
         }
 
-        // TODO: clone one or make mut all ?
-        let value = self.tail.last().unwrap().clone();
         let (ext, _) = Self::pop_external(&mut self.root, self.depth);
-        self.tail = ext.to_external();
-        self.len -= 1;
+        self.tail = ext.into_external();
 
         let mut root_killer = None;
         if let &mut Node::Internal(ref mut r) = &mut self.root {
@@ -124,11 +241,237 @@ impl<V: Clone> CowVec<V> {
             }
         };
         if let Some(rk) = root_killer {
-            mem::replace(&mut self.root, rk);
+            self.root = rk;
+            self.depth -= 1;
+        }
+    }
+
+    // grows the root so that pushes do not reshape it
+    // until the tree holds NODE_SIZE^depth leaves.
+    pub fn ensure_depth(&mut self, depth: usize) {
+        assert!(depth.checked_mul(SHIFT).is_some_and(|s| s < usize::BITS as usize),
+                "depth too large to be indexed");
+        if self.tail_offset() == 0 {
+            // the path is built by the first leaf pushed
+            self.depth = self.depth.max(depth);
+            return;
+        }
+
+        while self.depth < depth {
+            let old_root = mem::replace(&mut self.root, new_internal_node());
+            self.root.make_internal_mut().push(old_root);
+            self.depth += 1;
+        }
+    }
+
+    // collapses the root levels above the depth needed for the length,
+    // such as those left by `ensure_depth`.
+    pub fn normalize(&mut self) {
+        while self.depth > Self::depth_for_len(self.len) {
+            let child = match self.root {
+                Node::Internal(ref r) => {
+                    debug_assert!(r.len() == 1, "over-tall root with several children");
+                    r[0].clone()
+                }
+                // the tree is empty
+                _ => {
+                    self.depth = 0;
+                    return;
+                }
+            };
+            self.root = child;
             self.depth -= 1;
         }
+    }
+
+    // the leaves after the new tail are cut whole, only the leaf becoming the tail
+    // is shortened. the cut elements are dropped once the vector has its new length.
+    pub fn truncate(&mut self, new_len: usize) {
+        if new_len >= self.len { return; }
+        if new_len == 0 { return self.clear(); }
+
+        let mut removed = Vec::new();
+        let mut old_tail = None;
+        if new_len <= self.tail_offset() {
+            let tail_start = (new_len - 1) & !MASK;
+            let shift = self.root_shift();
+            self.root.truncate_into(shift, tail_start + NODE_SIZE, &mut removed);
+            old_tail = Some(mem::take(&mut self.tail));
+            self.len = tail_start + NODE_SIZE;
+            self.pull_tail_leaf();
+        }
+
+        let keep = new_len - self.tail_offset();
+        let mut cut = Vec::new();
+        if keep < self.tail.len() {
+            match Arc::get_mut(&mut self.tail) {
+                Some(tail) => cut.extend(tail.drain(keep..)),
+                // only the kept elements of a shared tail are copied
+                None => self.tail = Arc::new(self.tail[..keep].iter().cloned().collect()),
+            }
+        }
+        self.len = new_len;
+        drop((removed, old_tail, cut));
+    }
+
+    // `self` is already empty when the elements are dropped
+    pub fn clear(&mut self) {
+        drop(mem::take(self));
+    }
+
+    // `on_remove` is only called once the vector is cut,
+    // so a panic in it or in a drop leaves a valid vector.
+    pub fn truncate_with<F: FnMut(V)>(&mut self, new_len: usize, on_remove: F) {
+        if new_len >= self.len { return; }
+        let mut removed = Vec::with_capacity(self.len - new_len);
+        while self.len > new_len {
+            removed.push(self.pop().unwrap());
+        }
+        removed.into_iter().rev().for_each(on_remove);
+    }
+
+    // an aligned split shares the leaves after `at`, which `truncate` cuts whole.
+    // otherwise the elements are popped, moving them out of unique leaves.
+    pub fn split_off(&mut self, at: usize) -> CowVec<V> {
+        assert!(at <= self.len, "split index out of bounds");
+        if at & MASK == 0 {
+            let res = self.suffix(at);
+            self.truncate(at);
+            return res;
+        }
+
+        let mut moved = Vec::with_capacity(self.len - at);
+        while self.len > at {
+            moved.push(self.pop().unwrap());
+        }
+        moved.into_iter().rev().collect()
+    }
+
+    // the elements from `at` onwards
+    fn suffix(&self, at: usize) -> CowVec<V> {
+        let mut res = CowVec::new();
+        if at & MASK == 0 {
+            // the leaves are aligned and can be shared
+            let mut start = at;
+            while start < self.len {
+                let leaf = self.leaf_arc(start);
+                res.push_leaf(leaf.clone());
+                start += leaf.len();
+            }
+        } else {
+            res.extend(self.iter_range(at, self.len).cloned());
+        }
+        res
+    }
+
+    // the first `count` elements, the full leaves are shared
+    fn prefix(&self, count: usize) -> CowVec<V> {
+        let mut res = CowVec::new();
+        let aligned = count & !MASK;
+        let mut start = 0;
+        while start < aligned {
+            let leaf = self.leaf_arc(start);
+            res.push_leaf(leaf.clone());
+            start += leaf.len();
+        }
+        res.extend(self.iter_range(aligned, count).cloned());
+        res
+    }
+
+    pub fn take_while<F: FnMut(&V) -> bool>(&self, mut pred: F) -> CowVec<V> {
+        let count = self.iter().position(|value| !pred(value)).unwrap_or(self.len);
+        self.prefix(count)
+    }
+
+    pub fn skip_while<F: FnMut(&V) -> bool>(&self, mut pred: F) -> CowVec<V> {
+        let count = self.iter().position(|value| !pred(value)).unwrap_or(self.len);
+        self.suffix(count)
+    }
+
+    pub fn split_off_back(&mut self, n: usize) -> CowVec<V> {
+        assert!(n <= self.len, "cannot split off more elements than the length");
+        let at = self.len - n;
+        self.split_off(at)
+    }
+
+    // keeps the elements from `at` onwards and returns the others
+    pub fn split_off_prefix(&mut self, at: usize) -> CowVec<V> {
+        let suffix = self.split_off(at);
+        mem::replace(self, suffix)
+    }
+
+    // if `f` panics, the elements from the leaf being processed onwards are kept
+    pub fn retain<F: FnMut(&V) -> bool>(&mut self, mut f: F) {
+        let leaves = mem::take(self).into_leaves();
+        let mut guard = RetainGuard { vec: self, leaves };
+        while let Some(leaf) = guard.leaves.front() {
+            let mut kept = [true; NODE_SIZE];
+            for (i, value) in leaf.iter().enumerate() {
+                kept[i] = f(value);
+            }
+
+            let leaf = guard.leaves.pop_front().unwrap();
+            guard.vec.push_kept(leaf, &kept);
+        }
+    }
+
+    // the elements of `leaf` marked in `kept`, moved out when the leaf is unique.
+    // the leaf itself is reused when aligned and entirely kept.
+    fn push_kept(&mut self, leaf: Arc<ExternalNode<V>>, kept: &[bool; NODE_SIZE]) {
+        let aligned = self.is_empty() || self.tail.len() == NODE_SIZE;
+        if aligned && kept[..leaf.len()].iter().all(|&k| k) {
+            return self.push_leaf(leaf);
+        }
+        match Arc::try_unwrap(leaf) {
+            Ok(leaf) => self.extend(leaf.into_iter().zip(kept).filter(|p| *p.1).map(|p| p.0)),
+            Err(leaf) => self.extend(leaf.iter().zip(kept).filter(|p| *p.1).map(|p| p.0.clone())),
+        }
+    }
+
+    // the leaves of the tree in order, then the tail
+    fn into_leaves(self) -> VecDeque<Arc<ExternalNode<V>>> {
+        let mut leaves = VecDeque::new();
+        self.root.move_leaves_into(&mut leaves);
+        if !self.tail.is_empty() {
+            leaves.push_back(self.tail);
+        }
+        leaves
+    }
+
+    // keeps the elements matching `pred` and returns the others
+    pub fn partition_off<F: FnMut(&V) -> bool>(&mut self, mut pred: F) -> CowVec<V> {
+        let mut removed = CowVec::new();
+        self.retain(|value| {
+            let keep = pred(value);
+            if !keep { removed.push(value.clone()); }
+            keep
+        });
+        removed
+    }
+
+    // `keep` must be sorted, unique and in bounds
+    pub fn retain_indices(&mut self, keep: &[usize]) {
+        assert!(keep.windows(2).all(|w| w[0] < w[1]), "indices must be sorted and unique");
+        assert!(keep.last().is_none_or(|&i| i < self.len), "index out of bounds");
+        let mut keep = keep.iter().peekable();
+        let mut index = 0;
+        self.retain(|_| {
+            let kept = keep.next_if_eq(&&index).is_some();
+            index += 1;
+            kept
+        });
+    }
 
-        return Some(value);
+    pub fn retain_range<R, F>(&mut self, range: R, mut pred: F)
+        where R: RangeBounds<usize>, F: FnMut(&V) -> bool
+    {
+        let (start, end) = range_bounds(range, self.len);
+        let mut index = 0;
+        self.retain(|value| {
+            let i = index;
+            index += 1;
+            i < start || i >= end || pred(value)
+        });
     }
 
     fn pop_external(node: &mut Node<V>, depth: usize) -> (Node<V>, bool) {
@@ -153,138 +496,4258 @@ impl<V: Clone> CowVec<V> {
     }
 
     pub fn get_mut(&mut self, index: usize) -> &mut V {
+        assert!(index < self.len, "index out of bounds");
         if index >= self.tail_offset() {
             return &mut Arc::make_mut(&mut self.tail)[index & MASK];
         }
 
-        Self::get_external_mut(&mut self.root, index, self.depth * SHIFT)
+        let shift = self.root_shift();
+        Self::get_external_mut(&mut self.root, index, shift)
+    }
+
+    // a copy of the element at `i` and the element at `j`, which may be the same
+    pub fn get_pair_mut_or_clone(&mut self, i: usize, j: usize) -> (V, &mut V) {
+        let value = self.get(i).clone();
+        (value, self.get_mut(j))
+    }
+
+    // the shift used to index the children of the root
+    fn root_shift(&self) -> usize {
+        let shift = self.depth * SHIFT;
+        debug_assert!(shift < usize::BITS as usize, "tree too deep to be indexed");
+        shift
+    }
+
+    // the fraction of the leaf slots holding elements, 1.0 when empty
+    pub fn fill_factor(&self) -> f64 {
+        if self.len == 0 { return 1.0; }
+        let mut leaves = 0;
+        self.for_each_leaf(&mut |_| leaves += 1);
+        self.len as f64 / (leaves * NODE_SIZE) as f64
+    }
+
+    // the length up to which pushes keep the root at its depth
+    pub fn capacity(&self) -> usize {
+        subtree_capacity(self.root_shift()).saturating_add(NODE_SIZE)
     }
 
     fn tail_offset(&self) -> usize {
         self.len - self.tail.len()
     }
 
+    // returns the leaf holding `index`
+    fn leaf(&self, index: usize) -> &[V] {
+        self.leaf_arc(index)
+    }
+
+    fn leaf_arc(&self, index: usize) -> &Arc<ExternalNode<V>> {
+        if index >= self.tail_offset() {
+            return &self.tail;
+        }
+
+        let mut node = &self.root;
+        let mut shift = self.root_shift();
+        loop {
+            match *node {
+                Node::External(ref n) => return n,
+                Node::Internal(ref n) => {
+                    node = &n[(index >> shift) & MASK];
+                    shift -= SHIFT;
+                }
+                Node::Empty => unreachable!(),
+            }
+        }
+    }
+
     fn get_external_mut(node: &mut Node<V>,
                         index: usize,
                         shift: usize) -> &mut V {
-        match node {
-            &mut Node::External(ref mut n) => {
+        match *node {
+            Node::External(ref mut n) => {
                 &mut Arc::make_mut(n)[index & MASK]
             }
-            &mut Node::Internal(ref mut n) => {
+            Node::Internal(ref mut n) => {
                 let sub_index = (index >> shift) & MASK;
                 let next = &mut Arc::make_mut(n)[sub_index];
                 Self::get_external_mut(next, index, shift - SHIFT)
             }
-            &mut Node::Empty => unreachable!(),
+            Node::Empty => unreachable!(),
+        }
+    }
+
+    // returns the leaf holding `index`, made unique along the path
+    fn leaf_mut(&mut self, index: usize) -> &mut [V] {
+        if index >= self.tail_offset() {
+            return Arc::make_mut(&mut self.tail).as_mut_slice();
+        }
+
+        let shift = self.root_shift();
+        Self::leaf_external_mut(&mut self.root, index, shift)
+    }
+
+    fn leaf_external_mut(node: &mut Node<V>,
+                         index: usize,
+                         shift: usize) -> &mut [V] {
+        match *node {
+            Node::External(ref mut n) => Arc::make_mut(n).as_mut_slice(),
+            Node::Internal(ref mut n) => {
+                let sub_index = (index >> shift) & MASK;
+                let next = &mut Arc::make_mut(n)[sub_index];
+                Self::leaf_external_mut(next, index, shift - SHIFT)
+            }
+            Node::Empty => unreachable!(),
+        }
+    }
+
+    pub fn map_in_place_indexed<F: FnMut(usize, &mut V)>(&mut self, mut f: F) {
+        let len = self.len;
+        let mut start = 0;
+        while start < len {
+            let leaf = self.leaf_mut(start);
+            for (i, value) in leaf.iter_mut().enumerate() {
+                f(start + i, value);
+            }
+            start += leaf.len();
+        }
+    }
+
+    // applies `f` to the elements of the leaves not shared with `reference`
+    pub fn iter_mut_changed<F>(&mut self, reference: &CowVec<V>, mut f: F)
+        where F: FnMut(usize, &mut V)
+    {
+        let len = self.len;
+        let mut start = 0;
+        while start < len {
+            let shared = start < reference.len &&
+                Arc::ptr_eq(self.leaf_arc(start), reference.leaf_arc(start));
+            if shared {
+                start += NODE_SIZE;
+                continue;
+            }
+
+            let leaf = self.leaf_mut(start);
+            for (i, value) in leaf.iter_mut().enumerate() {
+                f(start + i, value);
+            }
+            start += leaf.len();
+        }
+    }
+
+    pub fn updated(&self, index: usize, value: V) -> CowVec<V> {
+        let mut res = self.clone();
+        *res.get_mut(index) = value;
+        res
+    }
+
+    pub fn first_difference(&self, other: &Self) -> Option<usize> where V: PartialEq {
+        let len = self.len.min(other.len);
+        let tree_len = self.tail_offset().min(other.tail_offset());
+        let mut start = self.shared_prefix_len(other).min(tree_len);
+        while start < len {
+            let (a, b) = (self.leaf_arc(start), other.leaf_arc(start));
+            let count = (a.len() - (start & MASK)).min(len - start);
+            if !Arc::ptr_eq(a, b) {
+                let a = &a[start & MASK..];
+                let b = &b[start & MASK..];
+                if let Some(i) = (0..count).position(|i| a[i] != b[i]) {
+                    return Some(start + i);
+                }
+            }
+            start += count;
         }
+
+        if self.len == other.len { None } else { Some(len) }
+    }
+
+    pub fn common_prefix_len(&self, other: &Self) -> usize where V: PartialEq {
+        self.first_difference(other).unwrap_or(self.len)
+    }
+
+    // length of the prefix held by tree nodes shared with `other`,
+    // may go past the end of the trees.
+    fn shared_prefix_len(&self, other: &CowVec<V>) -> usize {
+        if self.depth != other.depth { return 0; }
+        Node::shared_prefix_len(&self.root, &other.root, self.root_shift())
     }
 
-    pub fn get(&mut self, index: usize) -> &V {
+    pub fn get(&self, index: usize) -> &V {
+        assert!(index < self.len, "index out of bounds");
         if index >= self.tail_offset() {
             return &self.tail[index & MASK];
         }
 
-        Self::get_external(&self.root, index, self.depth * SHIFT)
+        Self::get_external(&self.root, index, self.root_shift())
+    }
+
+    /// Returns the element at `index` without checking the bounds.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than `self.len()`. Any other index is undefined
+    /// behavior, even one that falls inside the allocated tail.
+    pub unsafe fn get_unchecked(&self, index: usize) -> &V {
+        debug_assert!(index < self.len, "index out of bounds");
+        if index >= self.tail_offset() {
+            return self.tail.get_unchecked(index & MASK);
+        }
+
+        Self::get_external_unchecked(&self.root, index, self.root_shift())
+    }
+
+    unsafe fn get_external_unchecked(node: &Node<V>, index: usize, shift: usize) -> &V {
+        match *node {
+            Node::External(ref n) => n.get_unchecked(index & MASK),
+            Node::Internal(ref n) => {
+                let sub_index = (index >> shift) & MASK;
+                Self::get_external_unchecked(n.get_unchecked(sub_index), index, shift - SHIFT)
+            }
+            Node::Empty => unreachable!(),
+        }
+    }
+
+    /// Returns the element at `index` without checking the bounds,
+    /// the nodes along its path are made unique as with `get_mut`.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than `self.len()`. Any other index is undefined
+    /// behavior, even one that falls inside the allocated tail.
+    pub unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut V {
+        debug_assert!(index < self.len, "index out of bounds");
+        if index >= self.tail_offset() {
+            return Arc::make_mut(&mut self.tail).get_unchecked_mut(index & MASK);
+        }
+
+        let shift = self.root_shift();
+        Self::get_external_unchecked_mut(&mut self.root, index, shift)
+    }
+
+    unsafe fn get_external_unchecked_mut(node: &mut Node<V>,
+                                         index: usize,
+                                         shift: usize) -> &mut V {
+        match *node {
+            Node::External(ref mut n) => Arc::make_mut(n).get_unchecked_mut(index & MASK),
+            Node::Internal(ref mut n) => {
+                let sub_index = (index >> shift) & MASK;
+                let next = Arc::make_mut(n).get_unchecked_mut(sub_index);
+                Self::get_external_unchecked_mut(next, index, shift - SHIFT)
+            }
+            Node::Empty => unreachable!(),
+        }
+    }
+
+    pub fn get_or<F: FnOnce() -> String>(&self, index: usize, msg: F) -> &V {
+        if index >= self.len {
+            panic!("{}", msg());
+        }
+        self.get(index)
     }
 
     fn get_external(node: &Node<V>, index: usize, shift: usize) -> &V {
-        match node {
-            &Node::External(ref n) => &n[index & MASK],
-            &Node::Internal(ref n) => {
+        match *node {
+            Node::External(ref n) => &n[index & MASK],
+            Node::Internal(ref n) => {
                 let sub_index = (index >> shift) & MASK;
                 Self::get_external(&n[sub_index], index, shift - SHIFT)
             }
-            &Node::Empty => unreachable!(),
+            Node::Empty => unreachable!(),
+        }
+    }
+
+    pub fn get_or_default(&self, index: usize) -> V where V: Default {
+        if index < self.len {
+            self.get(index).clone()
+        } else {
+            V::default()
         }
     }
 
     pub fn swap_remove(&mut self, index: usize) -> V {
-        // TODO: is there
+        assert!(index < self.len, "swap_remove index out of bounds");
+        let last_index = self.len - 1;
         let last = self.pop().unwrap();
-        if index == self.len {
+        if index == last_index {
             last
         } else {
             mem::replace(self.get_mut(index), last)
         }
     }
-}
-
-fn new_internal_node<V>() -> Node<V> {
-    Node::Internal(Arc::new(InternalNode::new()))
-}
 
-fn new_external_node<V>(value: V) -> Arc<ExternalNode<V>> {
-    let mut n = ExternalNode::new();
-    n.push(value);
-    Arc::new(n)
-}
-
-impl<V: Clone> Node<V> {
-    fn make_internal_mut(&mut self) -> &mut InternalNode<V> {
-        match self {
-            &mut Node::Internal(ref mut n) => Arc::make_mut(n),
-            _ => panic!("expected internal node"),
+    pub fn insert(&mut self, index: usize, value: V) {
+        assert!(index <= self.len, "insertion index out of bounds");
+        let mut carry = value;
+        for i in index..self.len {
+            carry = mem::replace(self.get_mut(i), carry);
         }
+        self.push(carry);
     }
 
-    fn to_external(self) -> Arc<ExternalNode<V>> {
-        match self {
-            Node::External(n) => n,
-            _ => panic!("expected external node"),
+    pub fn remove(&mut self, index: usize) -> V {
+        assert!(index < self.len, "removal index out of bounds");
+        let mut carry = self.pop().unwrap();
+        for i in (index..self.len).rev() {
+            carry = mem::replace(self.get_mut(i), carry);
         }
+        carry
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    pub fn is_sorted(&self) -> bool where V: PartialOrd {
+        self.iter().zip(self.iter().skip(1)).all(|(a, b)| a <= b)
+    }
 
-    #[test]
-    fn push_pop() {
-        let mut v = CowVec::new();
-        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
-        for i in 0..n {
-            v.push(i);
+    // a leaf-aligned vector keeps its leaves, each reversed in place
+    // if it is unique, otherwise the elements are moved out from the back.
+    pub fn reverse(&mut self) {
+        let old = mem::take(self);
+        if old.len & MASK != 0 {
+            return self.extend_batched(old.into_iter().rev());
         }
-        for i in 0..n {
-            assert!(v.pop() == Some(n - 1 - i));
+
+        let mut leaves = VecDeque::new();
+        old.root.move_leaves_into(&mut leaves);
+        if !old.tail.is_empty() {
+            leaves.push_back(old.tail);
+        }
+        while let Some(mut leaf) = leaves.pop_back() {
+            Arc::make_mut(&mut leaf).reverse();
+            self.push_leaf(leaf);
         }
-        assert!(v.pop() == None);
     }
 
-    #[test]
-    fn push_set_get() {
-        let mut v = CowVec::new();
-        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+    pub fn reversed(&self) -> CowVec<V> {
+        let mut res = CowVec::new();
+        res.extend_batched(self.iter().rev().cloned());
+        res
+    }
 
-        for i in 0..n {
-            v.push(i);
+    pub fn sort(&mut self) where V: Ord {
+        self.sort_by(|a, b| a.cmp(b));
+    }
+
+    // sorted vectors are left untouched, keeping their leaves shared.
+    // if `compare` panics, the elements are put back in an unspecified order.
+    pub fn sort_by<F>(&mut self, mut compare: F)
+        where F: FnMut(&V, &V) -> Ordering
+    {
+        let sorted = self.iter().zip(self.iter().skip(1))
+            .all(|(a, b)| compare(a, b) != Ordering::Greater);
+        if sorted { return; }
+
+        let values = mem::take(self).into_vec();
+        let mut guard = SortGuard { vec: self, values };
+        guard.values.sort_by(compare);
+    }
+
+    pub fn binary_search(&self, value: &V) -> Result<usize, usize> where V: Ord {
+        self.binary_search_by(|x| x.cmp(value))
+    }
+
+    pub fn binary_search_by<F>(&self, mut f: F) -> Result<usize, usize>
+        where F: FnMut(&V) -> Ordering
+    {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match f(self.get(mid)) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(mid),
+            }
         }
-        for i in 0..n {
-            if i % 2 == 0 {
-                *v.get_mut(i) = i / 2;
+        Err(low)
+    }
+
+    // the elements equal to `value` in a sorted vector,
+    // an empty range at the insertion point if there are none.
+    pub fn equal_range(&self, value: &V) -> Range<usize> where V: Ord {
+        let start = self.partition_point(|x| x < value);
+        let end = self.partition_point(|x| x <= value);
+        start..end
+    }
+
+    pub fn partition_point<F: FnMut(&V) -> bool>(&self, mut pred: F) -> usize {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.get(mid)) {
+                low = mid + 1;
+            } else {
+                high = mid;
             }
         }
-        for i in 0..n {
-            let expected = if i % 2 == 0 { i / 2 } else { i };
-            assert!(v.get(i) == &expected);
+        low
+    }
+
+    pub fn insert_sorted_unique(&mut self, value: V) -> bool where V: Ord {
+        match self.binary_search(&value) {
+            Ok(_) => false,
+            Err(index) => {
+                self.insert(index, value);
+                true
+            }
         }
     }
 
-    #[test]
-    fn swap_remove() {
-        let mut v = CowVec::new();
-        let h = 3 * NODE_SIZE;
-        let n = 2 * h;
+    // equal elements of `self` come first
+    pub fn merge_sorted(&self, other: &CowVec<V>) -> CowVec<V> where V: Ord {
+        let mut res = CowVec::new();
+        res.extend_merged(self.iter().cloned(), other.iter().cloned());
+        res
+    }
 
-        for i in 0..n {
-            v.push(i);
+    // the elements before the first inserted one keep their leaves
+    pub fn insert_many_sorted<I: IntoIterator<Item = V>>(&mut self, iter: I) where V: Ord {
+        let mut batch: Vec<V> = iter.into_iter().collect();
+        if batch.is_empty() { return; }
+        batch.sort();
+        let at = self.partition_point(|x| *x <= batch[0]);
+        let suffix = self.split_off(at);
+        self.extend_merged(suffix.into_iter(), batch.into_iter());
+    }
+
+    // on equal elements, the ones from `a` go first
+    fn extend_merged<A, B>(&mut self, a: A, b: B)
+        where V: Ord, A: Iterator<Item = V>, B: Iterator<Item = V>
+    {
+        let (mut a, mut b) = (a.peekable(), b.peekable());
+        let merged = iter::from_fn(|| match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => if y < x { b.next() } else { a.next() },
+            (Some(_), None) => a.next(),
+            (None, _) => b.next(),
+        });
+        self.extend(merged);
+    }
+
+    pub fn remove_sorted(&mut self, value: &V) -> bool where V: Ord {
+        match self.binary_search(value) {
+            Ok(index) => {
+                self.remove(index);
+                true
+            }
+            Err(_) => false,
         }
+    }
 
-        for i in 0..h {
+    pub fn clone_for_writing(&self) -> CowVec<V> {
+        let mut res = self.clone();
+        Arc::make_mut(&mut res.tail);
+        res
+    }
+
+    // copies the root and the tail if they are shared,
+    // the nodes below the root are still copied on their first write.
+    pub fn make_unique(&mut self) -> &mut Self {
+        Arc::make_mut(&mut self.tail);
+        match self.root {
+            Node::Internal(ref mut n) => { Arc::make_mut(n); }
+            Node::External(ref mut n) => { Arc::make_mut(n); }
+            Node::Empty => {}
+        }
+        self
+    }
+
+    // moves the elements out of the uniquely owned leaves, clones the others
+    pub fn into_vec(self) -> Vec<V> {
+        let mut res = Vec::with_capacity(self.len);
+        self.root.move_into(&mut res);
+        Node::External(self.tail).move_into(&mut res);
+        res
+    }
+
+    pub fn into_boxed_slice(self) -> Box<[V]> {
+        // the capacity is exact so no reallocation happens
+        self.into_vec().into_boxed_slice()
+    }
+
+    pub fn try_into_array<const N: usize>(self) -> Result<[V; N], CowVec<V>> {
+        if self.len != N { return Err(self); }
+        let values: ArrayVec<V, N> = self.into_iter().collect();
+        Ok(values.into_inner().ok().unwrap())
+    }
+
+    pub fn snapshot(&self) -> Snapshot<V> {
+        Snapshot { vec: self.clone() }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot<V>) {
+        *self = snapshot.vec.clone();
+    }
+
+    pub fn downgrade(&self) -> WeakVec<V> {
+        let root = match self.root {
+            Node::Internal(ref node) => WeakNode::Internal(Arc::downgrade(node)),
+            Node::External(ref node) => WeakNode::External(Arc::downgrade(node)),
+            Node::Empty => WeakNode::Empty,
+        };
+        WeakVec {
+            root,
+            depth: self.depth,
+            tail: Arc::downgrade(&self.tail),
+            len: self.len,
+        }
+    }
+
+    // counts the nodes of `self` (tail included) also used by `other`
+    pub fn shared_node_count(&self, other: &CowVec<V>) -> usize {
+        let mut other_nodes = HashSet::new();
+        other.for_each_node(&mut |p| { other_nodes.insert(p); });
+        let mut count = 0;
+        self.for_each_node(&mut |p| {
+            if other_nodes.contains(&p) { count += 1; }
+        });
+        count
+    }
+
+    fn for_each_leaf<F: FnMut(&Arc<ExternalNode<V>>)>(&self, f: &mut F) {
+        self.root.for_each_leaf(f);
+        f(&self.tail);
+    }
+
+    // the leaves of the tree in order, then the tail (empty for an empty vector),
+    // each with the address of its allocation: equal addresses are shared leaves.
+    pub fn for_each_leaf_arc<F: FnMut(*const (), &[V])>(&self, mut f: F) {
+        self.for_each_leaf(&mut |leaf| f(Arc::as_ptr(leaf) as *const (), &leaf[..]));
+    }
+
+    fn for_each_node<F: FnMut(*const ())>(&self, f: &mut F) {
+        self.root.for_each_node(f);
+        f(Arc::as_ptr(&self.tail) as *const ());
+    }
+
+    // panics if the internal invariants are broken
+    pub fn assert_valid(&self) {
+        assert!(self.tail.len() <= NODE_SIZE, "tail overflow");
+        assert!(self.len == 0 || !self.tail.is_empty(), "empty tail");
+        assert!(self.tail.len() <= self.len, "tail longer than the vector");
+        let tree_len = self.tail_offset();
+        assert!(tree_len.is_multiple_of(NODE_SIZE), "partial leaf in the tree");
+        // deeper trees come from `ensure_depth`
+        assert!(self.depth >= Self::depth_for_len(self.len), "tree too shallow");
+        let counted = match self.root {
+            Node::Empty => 0,
+            Node::External(_) => {
+                assert!(self.depth == 0, "leaf root above depth 0");
+                self.root.assert_valid(0)
+            }
+            Node::Internal(_) => {
+                assert!(self.depth > 0, "internal root at depth 0");
+                self.root.assert_valid(self.root_shift())
+            }
+        };
+        assert!(counted == tree_len, "tree length mismatch");
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        self.iter_range(0, self.len)
+    }
+
+    pub fn split_iter_at(&self, mid: usize) -> (Iter<'_, V>, Iter<'_, V>) {
+        assert!(mid <= self.len, "split index out of bounds");
+        (self.iter_range(0, mid), self.iter_range(mid, self.len))
+    }
+
+    fn iter_range(&self, start: usize, end: usize) -> Iter<'_, V> {
+        debug_assert!(start <= end && end <= self.len);
+        Iter {
+            vec: self,
+            front: start,
+            back: end,
+            front_leaf: &[],
+            back_leaf: &[],
+        }
+    }
+
+    pub fn iter_chain<'a>(&'a self, other: &'a CowVec<V>)
+        -> impl DoubleEndedIterator<Item = &'a V>
+    {
+        self.iter().chain(other.iter())
+    }
+
+    pub fn leaf_spans(&self) -> impl Iterator<Item = (usize, &[V])> + '_ {
+        let mut start = 0;
+        iter::from_fn(move || {
+            if start == self.len { return None; }
+            let leaf = self.leaf(start);
+            let span = (start, leaf);
+            start += leaf.len();
+            Some(span)
+        })
+    }
+
+    // the full leaves of the tree then the tail
+    pub fn leaf_aligned_chunks(&self) -> impl Iterator<Item = &[V]> + '_ {
+        self.leaf_spans().map(|(_, leaf)| leaf)
+    }
+
+    // the windows inside a leaf are borrowed, the others are gathered
+    pub fn for_each_window<F: FnMut(&[V])>(&self, size: usize, mut f: F) {
+        assert!(size != 0, "window size must be non-zero");
+        if size > self.len { return; }
+        let mut gathered = Vec::with_capacity(size);
+        for start in 0..self.len - size + 1 {
+            let leaf = &self.leaf(start)[start & MASK..];
+            if leaf.len() >= size {
+                f(&leaf[..size]);
+            } else {
+                gathered.clear();
+                gathered.extend(self.iter_range(start, start + size).cloned());
+                f(&gathered);
+            }
+        }
+    }
+
+    // maps each chunk of `size` elements, the last one may be shorter.
+    // the chunks inside a leaf are borrowed, the others are gathered.
+    pub fn chunk_map<W, F>(&self, size: usize, mut f: F) -> CowVec<W>
+        where W: Clone, F: FnMut(&[V]) -> W
+    {
+        assert!(size != 0, "chunk size must be non-zero");
+        let mut gathered = Vec::with_capacity(size.min(self.len));
+        let mut res = CowVec::new();
+        let mut start = 0;
+        while start < self.len {
+            let end = self.len.min(start.saturating_add(size));
+            let leaf = &self.leaf(start)[start & MASK..];
+            if leaf.len() >= end - start {
+                res.push(f(&leaf[..end - start]));
+            } else {
+                gathered.clear();
+                gathered.extend(self.iter_range(start, end).cloned());
+                res.push(f(&gathered));
+            }
+            start = end;
+        }
+        res
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, V> {
+        let len = self.len;
+        self.iter_range_mut(0, len)
+    }
+
+    // the partial leaves at the ends of the range are made unique now,
+    // the others as they are visited.
+    fn iter_range_mut(&mut self, start: usize, end: usize) -> IterMut<'_, V> {
+        let len = self.len;
+        let mut leaves = self.leaf_arcs_mut(start, end).into_iter();
+        let mut front: &mut [V] = &mut [];
+        let mut back: &mut [V] = &mut [];
+        if start == end {
+            return IterMut { leaves, front, back, len: 0 };
+        }
+
+        let last_start = (end - 1) & !MASK;
+        if end < len.min(last_start + NODE_SIZE) {
+            back = &mut Arc::make_mut(leaves.next_back().unwrap())[..end - last_start];
+        }
+        if start & MASK != 0 {
+            if leaves.len() == 0 {
+                // the range is inside a single leaf
+                back = &mut mem::take(&mut back)[start & MASK..];
+            } else {
+                front = &mut Arc::make_mut(leaves.next().unwrap())[start & MASK..];
+            }
+        }
+        IterMut { leaves, front, back, len: end - start }
+    }
+
+    // the leaves covering the range, only the internal nodes above them
+    // are made unique.
+    fn leaf_arcs_mut(&mut self, start: usize, end: usize) -> Vec<&mut Arc<ExternalNode<V>>> {
+        debug_assert!(start <= end && end <= self.len);
+        let mut leaves = Vec::new();
+        if start == end { return leaves; }
+
+        let tail_offset = self.tail_offset();
+        if start < tail_offset {
+            let tree_end = end.min(tail_offset);
+            self.root.leaf_arcs_mut(0, self.root_shift(), start, tree_end, &mut leaves);
+        }
+        if end > tail_offset {
+            leaves.push(&mut self.tail);
+        }
+        leaves
+    }
+
+    // returns the parts of the leaves covering the range,
+    // only these leaves are made unique.
+    fn leaves_mut(&mut self, start: usize, end: usize) -> Vec<&mut [V]> {
+        let mut offset = start & !MASK;
+        self.leaf_arcs_mut(start, end).into_iter().map(|leaf| {
+            let leaf = Arc::make_mut(leaf);
+            let (from, to) = (start.max(offset) - offset, end.min(offset + leaf.len()) - offset);
+            offset += NODE_SIZE;
+            &mut leaf[from..to]
+        }).collect()
+    }
+
+    // each leaf is made unique as it is visited
+    pub fn leaf_slices_mut(&mut self) -> impl Iterator<Item = &mut [V]> {
+        let len = self.len;
+        self.leaf_arcs_mut(0, len).into_iter().map(|leaf| Arc::make_mut(leaf).as_mut_slice())
+    }
+
+    pub fn get_range_mut<R: RangeBounds<usize>>(&mut self, range: R) -> RangeMut<'_, V> {
+        let (start, end) = range_bounds(range, self.len);
+        RangeMut { vec: self, start, end }
+    }
+
+    pub fn rchunks_exact(&self, size: usize) -> RChunksExact<'_, V> {
+        assert!(size != 0, "chunk size must be non-zero");
+        RChunksExact {
+            vec: self,
+            end: self.len,
+            remainder: self.len % size,
+            size,
+        }
+    }
+
+    pub fn unique(&self) -> CowVec<V> where V: Eq + Hash {
+        let mut seen = HashSet::new();
+        let mut res = CowVec::new();
+        for value in self.iter() {
+            if seen.insert(value) {
+                res.push(value.clone());
+            }
+        }
+        res
+    }
+
+    pub fn extend_from_slice(&mut self, values: &[V]) {
+        self.extend(values.iter().cloned());
+    }
+
+    pub fn append_n_copies(&mut self, value: &V, n: usize) {
+        let mut remaining = n;
+        while remaining > 0 && self.tail.len() < NODE_SIZE {
+            self.push(value.clone());
+            remaining -= 1;
+        }
+
+        // the full leaves are all the same
+        if remaining >= NODE_SIZE {
+            let leaf: Arc<ExternalNode<V>> = Arc::new(
+                iter::repeat_n(value.clone(), NODE_SIZE).collect()
+            );
+            while remaining >= NODE_SIZE {
+                self.push_leaf(leaf.clone());
+                remaining -= NODE_SIZE;
+            }
+        }
+        self.extend(iter::repeat_n(value.clone(), remaining));
+    }
+
+    pub fn interleave(&self, other: &CowVec<V>) -> CowVec<V> {
+        let (mut a, mut b) = (self.iter(), other.iter());
+        let mut from_self = true;
+        let values = iter::from_fn(|| {
+            let next = if from_self {
+                a.next().or_else(|| b.next())
+            } else {
+                b.next().or_else(|| a.next())
+            };
+            from_self = !from_self;
+            next
+        });
+        values.cloned().collect()
+    }
+
+    pub fn intersperse(&self, sep: V) -> CowVec<V> {
+        let mut res = CowVec::new();
+        let mut values = self.iter();
+        res.extend(values.next().cloned());
+        for value in values {
+            res.push(sep.clone());
+            res.push(value.clone());
+        }
+        res
+    }
+
+    // indices may repeat and come in any order
+    pub fn gather(&self, indices: &[usize]) -> CowVec<V> {
+        indices.iter().map(|&i| self.get(i).clone()).collect()
+    }
+
+    // writes `values[k]` at `indices[k]`, the last write to an index wins.
+    // nothing is written if an index is out of bounds.
+    pub fn scatter(&mut self, indices: &[usize], values: &[V]) {
+        assert!(indices.len() == values.len(), "as many indices as values are needed");
+        assert!(indices.iter().all(|&i| i < self.len), "index out of bounds");
+        for (&i, value) in indices.iter().zip(values) {
+            *self.get_mut(i) = value.clone();
+        }
+    }
+
+    pub fn map_while<W, F>(&self, f: F) -> CowVec<W>
+        where W: Clone, F: FnMut(&V) -> Option<W>
+    {
+        self.iter().map_while(f).collect()
+    }
+
+    pub fn scan<B, F>(&self, init: B, mut f: F) -> CowVec<B>
+        where B: Clone, F: FnMut(&mut B, &V) -> B
+    {
+        let mut state = init;
+        self.iter().map(|value| f(&mut state, value)).collect()
+    }
+
+    // `None` if the sum overflows
+    pub fn checked_sum(&self) -> Option<V> where V: CheckedAdd + Default {
+        self.iter().try_fold(V::default(), |sum, value| sum.checked_add(value))
+    }
+
+    pub fn prefix_sum(&self) -> CowVec<V> where V: Add<Output = V> {
+        let mut sum: Option<V> = None;
+        self.iter().map(|value| {
+            let next = match sum.take() {
+                Some(s) => s + value.clone(),
+                None => value.clone(),
+            };
+            sum = Some(next.clone());
+            next
+        }).collect()
+    }
+
+    pub fn all<F: FnMut(&V) -> bool>(&self, f: F) -> bool {
+        self.iter().all(f)
+    }
+
+    pub fn any<F: FnMut(&V) -> bool>(&self, f: F) -> bool {
+        self.iter().any(f)
+    }
+
+    // the first minimum on ties
+    pub fn min_by<F>(&self, mut compare: F) -> Option<&V>
+        where F: FnMut(&V, &V) -> Ordering
+    {
+        self.iter().fold(None, |best, x| match best {
+            Some(b) if compare(x, b) != Ordering::Less => Some(b),
+            _ => Some(x),
+        })
+    }
+
+    // the first maximum on ties
+    pub fn max_by<F>(&self, mut compare: F) -> Option<&V>
+        where F: FnMut(&V, &V) -> Ordering
+    {
+        self.iter().fold(None, |best, x| match best {
+            Some(b) if compare(x, b) != Ordering::Greater => Some(b),
+            _ => Some(x),
+        })
+    }
+
+    pub fn positions<F: FnMut(&V) -> bool>(&self, mut pred: F) -> CowVec<usize> {
+        self.iter().enumerate()
+            .filter(|&(_, value)| pred(value))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn dedup(&mut self) where V: PartialEq {
+        self.dedup_by(|a, b| a == b);
+    }
+
+    pub fn dedup_by_key<K, F>(&mut self, mut key: F)
+        where K: PartialEq, F: FnMut(&V) -> K
+    {
+        self.dedup_by(|a, b| key(a) == key(b));
+    }
+
+    // removes the elements for which `same_bucket(element, previous)` is true,
+    // `previous` being the last kept element.
+    pub fn dedup_by<F: FnMut(&V, &V) -> bool>(&mut self, mut same_bucket: F) {
+        let leaves = mem::take(self).into_leaves();
+        let mut dropped: Option<Arc<ExternalNode<V>>> = None;
+        for leaf in leaves {
+            // a leaf shared with the previous one, which was entirely dropped,
+            // compares the same elements against the same last kept element
+            if dropped.as_ref().is_some_and(|d| Arc::ptr_eq(d, &leaf)) { continue; }
+
+            let mut kept = [true; NODE_SIZE];
+            let mut last = self.tail.last();
+            for (i, value) in leaf.iter().enumerate() {
+                kept[i] = last.is_none_or(|l| !same_bucket(value, l));
+                if kept[i] { last = Some(value); }
+            }
+
+            // the leaves without duplicates are reused when aligned
+            if kept[..leaf.len()].iter().any(|&k| k) {
+                dropped = None;
+                self.push_kept(leaf, &kept);
+            } else {
+                dropped = Some(leaf);
+            }
+        }
+    }
+
+    pub fn frequencies(&self) -> HashMap<V, usize> where V: Eq + Hash {
+        let mut counts = HashMap::new();
+        for value in self.iter() {
+            *counts.entry(value.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    // the distinct elements in order of first occurrence with their counts
+    pub fn counts_in_order(&self) -> CowVec<(V, usize)> where V: Eq + Hash {
+        let mut positions = HashMap::new();
+        let mut counts: Vec<(V, usize)> = Vec::new();
+        for value in self.iter() {
+            let next = counts.len();
+            let i = *positions.entry(value).or_insert(next);
+            if i == next {
+                counts.push((value.clone(), 0));
+            }
+            counts[i].1 += 1;
+        }
+        CowVec::from_vec(counts)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<V: Clone + Sync> CowVec<V> {
+    // one task per leaf, in order
+    pub fn par_leaf_slices(&self) -> impl ::rayon::iter::IndexedParallelIterator<Item = &[V]> + '_ {
+        use rayon::prelude::*;
+        let leaves: Vec<&[V]> = self.leaf_spans().map(|(_, leaf)| leaf).collect();
+        leaves.into_par_iter()
+    }
+
+    // the elements are split between tasks, a leaf is only cut when a split
+    // falls inside it. each task makes its leaves unique as it visits them.
+    pub fn par_iter_mut(&mut self) -> impl ::rayon::iter::IndexedParallelIterator<Item = &mut V> + '_
+        where V: Send
+    {
+        let len = self.len;
+        let leaves = self.leaf_arcs_mut(0, len);
+        ParIterMut { leaves: leaves.into_iter().map(Some).collect(), len }
+    }
+}
+
+#[cfg(feature = "rayon")]
+struct ParIterMut<'a, V: 'a> {
+    leaves: Vec<Option<&'a mut Arc<ExternalNode<V>>>>,
+    len: usize,
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, V: Clone + Send + Sync + 'a> ::rayon::iter::ParallelIterator for ParIterMut<'a, V> {
+    type Item = &'a mut V;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+        where C: ::rayon::iter::plumbing::UnindexedConsumer<&'a mut V>
+    {
+        ::rayon::iter::plumbing::bridge(self, consumer)
+    }
+
+    fn opt_len(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, V: Clone + Send + Sync + 'a> ::rayon::iter::IndexedParallelIterator for ParIterMut<'a, V> {
+    fn drive<C: ::rayon::iter::plumbing::Consumer<&'a mut V>>(self, consumer: C) -> C::Result {
+        ::rayon::iter::plumbing::bridge(self, consumer)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn with_producer<CB>(mut self, callback: CB) -> CB::Output
+        where CB: ::rayon::iter::plumbing::ProducerCallback<&'a mut V>
+    {
+        // only the tail can be partial, it is made unique now and goes at the back
+        let back = match self.leaves.pop() {
+            Some(tail) => Arc::make_mut(tail.unwrap()).as_mut_slice(),
+            None => &mut [],
+        };
+        callback.callback(LeavesProducer { front: &mut [], leaves: &mut self.leaves[..], back })
+    }
+}
+
+// every leaf in `leaves` is full, `front` and `back` are the
+// pieces of leaves cut by previous splits (or the tail).
+#[cfg(feature = "rayon")]
+struct LeavesProducer<'b, 'a: 'b, V: 'a> {
+    front: &'a mut [V],
+    leaves: &'b mut [Option<&'a mut Arc<ExternalNode<V>>>],
+    back: &'a mut [V],
+}
+
+#[cfg(feature = "rayon")]
+impl<'b, 'a, V: Clone + Send + Sync + 'a> ::rayon::iter::plumbing::Producer
+    for LeavesProducer<'b, 'a, V>
+{
+    type Item = &'a mut V;
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter(self) -> IterMut<'a, V> {
+        let len = self.front.len() + self.leaves.len() * NODE_SIZE + self.back.len();
+        let leaves: Vec<_> = self.leaves.iter_mut().map(|leaf| leaf.take().unwrap()).collect();
+        IterMut { leaves: leaves.into_iter(), front: self.front, back: self.back, len }
+    }
+
+    fn min_len(&self) -> usize {
+        NODE_SIZE
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let LeavesProducer { front, leaves, back } = self;
+        if index <= front.len() {
+            let (left, right) = front.split_at_mut(index);
+            return (LeavesProducer { front: left, leaves: &mut [], back: &mut [] },
+                    LeavesProducer { front: right, leaves, back });
+        }
+        let index = index - front.len();
+        if index >= leaves.len() * NODE_SIZE {
+            let (left, right) = back.split_at_mut(index - leaves.len() * NODE_SIZE);
+            return (LeavesProducer { front, leaves, back: left },
+                    LeavesProducer { front: &mut [], leaves: &mut [], back: right });
+        }
+        let (left_leaves, rest) = leaves.split_at_mut(index >> SHIFT);
+        if index & MASK == 0 {
+            return (LeavesProducer { front, leaves: left_leaves, back: &mut [] },
+                    LeavesProducer { front: &mut [], leaves: rest, back });
+        }
+        // only the leaf the split falls in is made unique here
+        let (cut, right_leaves) = rest.split_first_mut().unwrap();
+        let cut = Arc::make_mut(cut.take().unwrap());
+        let (left, right) = cut.split_at_mut(index & MASK);
+        (LeavesProducer { front, leaves: left_leaves, back: left },
+         LeavesProducer { front: right, leaves: right_leaves, back })
+    }
+}
+
+impl<V: Clone> CowVec<CowVec<V>> {
+    // the leaves of the inner vectors are reused when aligned
+    pub fn flatten(self) -> CowVec<V> {
+        let mut res = CowVec::new();
+        for inner in self {
+            res.extend_from_cow(inner);
+        }
+        res
+    }
+}
+
+impl<A: Clone, B: Clone> CowVec<(A, B)> {
+    // each leaf of pairs gives one leaf of each side
+    pub fn unzip(&self) -> (CowVec<A>, CowVec<B>) {
+        let (mut a, mut b) = (CowVec::new(), CowVec::new());
+        for (_, leaf) in self.leaf_spans() {
+            a.push_leaf(Arc::new(leaf.iter().map(|p| p.0.clone()).collect()));
+            b.push_leaf(Arc::new(leaf.iter().map(|p| p.1.clone()).collect()));
+        }
+        (a, b)
+    }
+}
+
+impl CowVec<bool> {
+    pub fn count_true(&self) -> usize {
+        self.leaf_spans()
+            .map(|(_, leaf)| leaf.iter().filter(|&&b| b).count())
+            .sum()
+    }
+
+    // only the leaves covering the range are made unique
+    pub fn toggle_range<R: RangeBounds<usize>>(&mut self, range: R) {
+        let (start, end) = range_bounds(range, self.len);
+        for leaf in self.leaves_mut(start, end) {
+            for b in leaf.iter_mut() {
+                *b = !*b;
+            }
+        }
+    }
+}
+
+impl CowVec<u8> {
+    // copies the bytes starting at `offset` into `buf`,
+    // returns the number of bytes read.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len.saturating_sub(offset));
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done;
+            let leaf = &self.leaf(pos)[pos & MASK..];
+            let n = leaf.len().min(count - done);
+            buf[done..done + n].copy_from_slice(&leaf[..n]);
+            done += n;
+        }
+        count
+    }
+
+    // overwrites the bytes starting at `offset` with `buf`,
+    // the bytes going past the end are pushed.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) {
+        assert!(offset <= self.len, "write offset out of bounds");
+        let count = buf.len().min(self.len - offset);
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done;
+            let leaf = &mut self.leaf_mut(pos)[pos & MASK..];
+            let n = leaf.len().min(count - done);
+            leaf[..n].copy_from_slice(&buf[done..done + n]);
+            done += n;
+        }
+        self.extend(buf[count..].iter().cloned());
+    }
+}
+
+impl io::Write for CowVec<u8> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// An immutable version of a vector.
+// As it holds its own references to the nodes they are never mutated in place.
+#[derive(Debug, Clone)]
+pub struct Snapshot<V> {
+    vec: CowVec<V>,
+}
+
+impl<V> Deref for Snapshot<V> {
+    type Target = CowVec<V>;
+
+    fn deref(&self) -> &CowVec<V> {
+        &self.vec
+    }
+}
+
+// A version of a vector that does not keep its nodes alive.
+// Only the root and the tail are referenced weakly: the children of the root
+// are released with it, but a subtree or leaf may still outlive the vector
+// through another version sharing it, which this cannot observe.
+#[derive(Debug, Clone)]
+pub struct WeakVec<V> {
+    root: WeakNode<V>,
+    depth: usize,
+    tail: Weak<ExternalNode<V>>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+enum WeakNode<V> {
+    Internal(Weak<InternalNode<V>>),
+    External(Weak<ExternalNode<V>>),
+    Empty,
+}
+
+impl<V> WeakVec<V> {
+    // returns `None` once the root or the tail of the version has been dropped
+    pub fn upgrade(&self) -> Option<CowVec<V>> {
+        let root = match self.root {
+            WeakNode::Internal(ref node) => Node::Internal(node.upgrade()?),
+            WeakNode::External(ref node) => Node::External(node.upgrade()?),
+            WeakNode::Empty => Node::Empty,
+        };
+        Some(CowVec {
+            root,
+            depth: self.depth,
+            tail: self.tail.upgrade()?,
+            len: self.len,
+        })
+    }
+}
+
+pub struct CowBytesReader {
+    bytes: CowVec<u8>,
+    position: usize,
+}
+
+impl CowBytesReader {
+    pub fn new(bytes: CowVec<u8>) -> Self {
+        CowBytesReader { bytes, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn into_inner(self) -> CowVec<u8> {
+        self.bytes
+    }
+}
+
+impl io::Read for CowBytesReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.bytes.read_at(self.position, buf);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl ::bytes::Buf for CowBytesReader {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    fn chunk(&self) -> &[u8] {
+        if self.position == self.bytes.len() { return &[]; }
+        &self.bytes.leaf(self.position)[self.position & MASK..]
+    }
+
+    fn advance(&mut self, count: usize) {
+        assert!(count <= self.remaining(), "cannot advance past the end");
+        self.position += count;
+    }
+}
+
+impl<V: Clone> Default for CowVec<V> {
+    fn default() -> Self {
+        CowVec::new()
+    }
+}
+
+impl<V: Clone> Extend<V> for CowVec<V> {
+    fn extend<I: IntoIterator<Item = V>>(&mut self, iter: I) {
+        self.extend_batched(iter.into_iter());
+    }
+}
+
+impl<'a, V: Clone + 'a> Extend<&'a V> for CowVec<V> {
+    fn extend<I: IntoIterator<Item = &'a V>>(&mut self, iter: I) {
+        self.extend_batched(iter.into_iter().cloned());
+    }
+}
+
+// vectors of the same length have the same leaf boundaries,
+// so whole leaves are compared and the shared subtrees skipped.
+impl<V: Clone + PartialEq> PartialEq for CowVec<V> {
+    fn eq(&self, other: &CowVec<V>) -> bool {
+        if self.len != other.len { return false; }
+        let mut start = self.shared_prefix_len(other).min(self.tail_offset());
+        while start < self.len {
+            let (a, b) = (self.leaf_arc(start), other.leaf_arc(start));
+            if !Arc::ptr_eq(a, b) && a[..] != b[..] { return false; }
+            start += a.len();
+        }
+        true
+    }
+}
+
+impl<V: Clone + Eq> Eq for CowVec<V> {}
+
+// hashes like a slice of the elements
+impl<V: Clone + Hash> Hash for CowVec<V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len);
+        for (_, leaf) in self.leaf_spans() {
+            V::hash_slice(leaf, state);
+        }
+    }
+}
+
+impl<V: Clone> From<Vec<V>> for CowVec<V> {
+    fn from(values: Vec<V>) -> Self {
+        CowVec::from_vec(values)
+    }
+}
+
+// the remaining whole leaves are reused while aligned
+impl<V: Clone> From<IntoIter<V>> for CowVec<V> {
+    fn from(iter: IntoIter<V>) -> Self {
+        let IntoIter { leaves, front, back, .. } = iter;
+        let mut res = CowVec::new();
+        if let Some(mut front) = front {
+            res.extend(iter::from_fn(|| front.next()));
+        }
+        for leaf in leaves {
+            if res.is_empty() || res.tail.len() == NODE_SIZE {
+                res.push_leaf(leaf);
+            } else {
+                let mut leaf = LeafIntoIter::new(leaf);
+                res.extend(iter::from_fn(|| leaf.next()));
+            }
+        }
+        if let Some(mut back) = back {
+            res.extend(iter::from_fn(|| back.next()));
+        }
+        res
+    }
+}
+
+impl<V: Clone> FromIterator<V> for CowVec<V> {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut v = CowVec::new();
+        v.append_iter(iter);
+        v
+    }
+}
+
+impl<V: Clone> IntoIterator for CowVec<V> {
+    type Item = V;
+    type IntoIter = IntoIter<V>;
+
+    fn into_iter(self) -> IntoIter<V> {
+        let len = self.len;
+        IntoIter {
+            leaves: self.into_leaves(),
+            front: None,
+            back: None,
+            len,
+        }
+    }
+}
+
+impl<'a, V: Clone> IntoIterator for &'a mut CowVec<V> {
+    type Item = &'a mut V;
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter(self) -> IterMut<'a, V> {
+        self.iter_mut()
+    }
+}
+
+impl<'a, V: Clone> IntoIterator for &'a CowVec<V> {
+    type Item = &'a V;
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+// Mutable access to a range, indexed from its start.
+pub struct RangeMut<'a, V: 'a> {
+    vec: &'a mut CowVec<V>,
+    start: usize,
+    end: usize,
+}
+
+impl<'a, V: Clone> RangeMut<'a, V> {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn get(&self, index: usize) -> &V {
+        assert!(index < self.len(), "index out of range bounds");
+        self.vec.get(self.start + index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut V {
+        assert!(index < self.len(), "index out of range bounds");
+        self.vec.get_mut(self.start + index)
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        self.vec.iter_range(self.start, self.end)
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, V> {
+        self.vec.iter_range_mut(self.start, self.end)
+    }
+}
+
+// Moves the elements out of the uniquely owned leaves, clones the others.
+// Fused: keeps returning `None` once exhausted, from both ends.
+pub struct IntoIter<V> {
+    leaves: VecDeque<Arc<ExternalNode<V>>>,
+    front: Option<LeafIntoIter<V>>,
+    back: Option<LeafIntoIter<V>>,
+    len: usize,
+}
+
+enum LeafIntoIter<V> {
+    Owned(::arrayvec::IntoIter<V, NODE_SIZE>),
+    // the remaining range of a shared leaf
+    Shared(Arc<ExternalNode<V>>, usize, usize),
+}
+
+impl<V: Clone> LeafIntoIter<V> {
+    fn new(leaf: Arc<ExternalNode<V>>) -> Self {
+        match Arc::try_unwrap(leaf) {
+            Ok(leaf) => LeafIntoIter::Owned(leaf.into_iter()),
+            Err(leaf) => {
+                let len = leaf.len();
+                LeafIntoIter::Shared(leaf, 0, len)
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<V> {
+        match *self {
+            LeafIntoIter::Owned(ref mut it) => it.next(),
+            LeafIntoIter::Shared(ref leaf, ref mut start, end) => {
+                if *start == end { return None; }
+                *start += 1;
+                Some(leaf[*start - 1].clone())
+            }
+        }
+    }
+
+    fn next_back(&mut self) -> Option<V> {
+        match *self {
+            LeafIntoIter::Owned(ref mut it) => it.next_back(),
+            LeafIntoIter::Shared(ref leaf, start, ref mut end) => {
+                if start == *end { return None; }
+                *end -= 1;
+                Some(leaf[*end].clone())
+            }
+        }
+    }
+}
+
+impl<V: Clone> Iterator for IntoIter<V> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        loop {
+            if let Some(value) = self.front.as_mut().and_then(|f| f.next()) {
+                self.len -= 1;
+                return Some(value);
+            }
+            match self.leaves.pop_front() {
+                Some(leaf) => self.front = Some(LeafIntoIter::new(leaf)),
+                None => {
+                    // the cursors met in the back leaf
+                    let value = self.back.as_mut().and_then(|b| b.next());
+                    if value.is_some() { self.len -= 1; }
+                    return value;
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<V: Clone> DoubleEndedIterator for IntoIter<V> {
+    fn next_back(&mut self) -> Option<V> {
+        loop {
+            if let Some(value) = self.back.as_mut().and_then(|b| b.next_back()) {
+                self.len -= 1;
+                return Some(value);
+            }
+            match self.leaves.pop_back() {
+                Some(leaf) => self.back = Some(LeafIntoIter::new(leaf)),
+                None => {
+                    // the cursors met in the front leaf
+                    let value = self.front.as_mut().and_then(|f| f.next_back());
+                    if value.is_some() { self.len -= 1; }
+                    return value;
+                }
+            }
+        }
+    }
+}
+
+impl<V: Clone> ExactSizeIterator for IntoIter<V> {}
+impl<V: Clone> FusedIterator for IntoIter<V> {}
+
+// the chunks and the remainder borrow the vector, like `slice::rchunks_exact`
+pub struct RChunksExact<'a, V: 'a> {
+    vec: &'a CowVec<V>,
+    end: usize,
+    remainder: usize,
+    size: usize,
+}
+
+impl<'a, V: Clone> RChunksExact<'a, V> {
+    pub fn remainder(&self) -> Iter<'a, V> {
+        self.vec.iter_range(0, self.remainder)
+    }
+}
+
+impl<'a, V: Clone> Iterator for RChunksExact<'a, V> {
+    type Item = Iter<'a, V>;
+
+    fn next(&mut self) -> Option<Iter<'a, V>> {
+        if self.end == self.remainder { return None; }
+        let start = self.end - self.size;
+        let chunk = self.vec.iter_range(start, self.end);
+        self.end = start;
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.end - self.remainder) / self.size;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, V: Clone> ExactSizeIterator for RChunksExact<'a, V> {}
+
+// Fused: keeps returning `None` once exhausted, from both ends.
+pub struct Iter<'a, V: 'a> {
+    vec: &'a CowVec<V>,
+    front: usize,
+    back: usize,
+    // remaining parts of the leaves under the cursors,
+    // they may extend past the other cursor.
+    front_leaf: &'a [V],
+    back_leaf: &'a [V],
+}
+
+impl<'a, V: Clone> Iterator for Iter<'a, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        if self.front == self.back { return None; }
+        if self.front_leaf.is_empty() {
+            self.front_leaf = &self.vec.leaf(self.front)[self.front & MASK..];
+        }
+
+        let (value, rest) = self.front_leaf.split_first().unwrap();
+        self.front_leaf = rest;
+        self.front += 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.back - self.front;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, V: Clone> DoubleEndedIterator for Iter<'a, V> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        if self.front == self.back { return None; }
+        if self.back_leaf.is_empty() {
+            let last = self.back - 1;
+            self.back_leaf = &self.vec.leaf(last)[..(last & MASK) + 1];
+        }
+
+        let (value, rest) = self.back_leaf.split_last().unwrap();
+        self.back_leaf = rest;
+        self.back -= 1;
+        Some(value)
+    }
+}
+
+impl<'a, V: Clone> ExactSizeIterator for Iter<'a, V> {}
+impl<'a, V: Clone> FusedIterator for Iter<'a, V> {}
+
+// Fused: keeps returning `None` once exhausted, from both ends.
+pub struct IterMut<'a, V: 'a> {
+    leaves: ::std::vec::IntoIter<&'a mut Arc<ExternalNode<V>>>,
+    front: &'a mut [V],
+    back: &'a mut [V],
+    len: usize,
+}
+
+impl<'a, V: Clone> Iterator for IterMut<'a, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        loop {
+            if let Some((value, rest)) = mem::take(&mut self.front).split_first_mut() {
+                self.front = rest;
+                self.len -= 1;
+                return Some(value);
+            }
+            match self.leaves.next() {
+                Some(leaf) => self.front = Arc::make_mut(leaf).as_mut_slice(),
+                None => mem::swap(&mut self.front, &mut self.back),
+            }
+            if self.front.is_empty() && self.leaves.len() == 0 { return None; }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, V: Clone> DoubleEndedIterator for IterMut<'a, V> {
+    fn next_back(&mut self) -> Option<&'a mut V> {
+        loop {
+            if let Some((value, rest)) = mem::take(&mut self.back).split_last_mut() {
+                self.back = rest;
+                self.len -= 1;
+                return Some(value);
+            }
+            match self.leaves.next_back() {
+                Some(leaf) => self.back = Arc::make_mut(leaf).as_mut_slice(),
+                None => mem::swap(&mut self.front, &mut self.back),
+            }
+            if self.back.is_empty() && self.leaves.len() == 0 { return None; }
+        }
+    }
+}
+
+impl<'a, V: Clone> ExactSizeIterator for IterMut<'a, V> {}
+impl<'a, V: Clone> FusedIterator for IterMut<'a, V> {}
+
+pub fn zip_with<A, B, W, F>(a: &CowVec<A>, b: &CowVec<B>, mut f: F) -> CowVec<W>
+    where A: Clone, B: Clone, W: Clone, F: FnMut(&A, &B) -> W
+{
+    a.iter().zip(b.iter()).map(|(x, y)| f(x, y)).collect()
+}
+
+// restores the unprocessed leaves, even on panic
+struct RetainGuard<'a, V: Clone + 'a> {
+    vec: &'a mut CowVec<V>,
+    leaves: VecDeque<Arc<ExternalNode<V>>>,
+}
+
+impl<'a, V: Clone> Drop for RetainGuard<'a, V> {
+    fn drop(&mut self) {
+        for leaf in self.leaves.drain(..) {
+            self.vec.push_kept(leaf, &[true; NODE_SIZE]);
+        }
+    }
+}
+
+// puts the values back, even on panic
+struct SortGuard<'a, V: Clone + 'a> {
+    vec: &'a mut CowVec<V>,
+    values: Vec<V>,
+}
+
+impl<'a, V: Clone> Drop for SortGuard<'a, V> {
+    fn drop(&mut self) {
+        *self.vec = CowVec::from_vec(mem::take(&mut self.values));
+    }
+}
+
+pub fn concat_all<V: Clone>(parts: &[&CowVec<V>]) -> CowVec<V> {
+    let mut res = CowVec::new();
+    for part in parts {
+        res.extend_from_cow((*part).clone());
+    }
+    res
+}
+
+fn range_bounds<R: RangeBounds<usize>>(range: R, len: usize) -> (usize, usize) {
+    let start = match range.start_bound() {
+        Bound::Included(&s) => s,
+        Bound::Excluded(&s) => s.checked_add(1).expect("range start overflows usize"),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&e) => e.checked_add(1).expect("range end overflows usize"),
+        Bound::Excluded(&e) => e,
+        Bound::Unbounded => len,
+    };
+    assert!(start <= end, "range start is greater than range end");
+    assert!(end <= len, "range end out of bounds");
+    (start, end)
+}
+
+pub trait CheckedAdd: Sized {
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+}
+
+macro_rules! impl_checked_add {
+    ($($t:ty)*) => {
+        $(impl CheckedAdd for $t {
+            fn checked_add(&self, other: &$t) -> Option<$t> {
+                <$t>::checked_add(*self, *other)
+            }
+        })*
+    }
+}
+
+impl_checked_add!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize);
+
+// number of elements a full node indexing its children with `shift` holds,
+// saturates instead of overflowing.
+fn subtree_capacity(shift: usize) -> usize {
+    if shift + SHIFT >= usize::BITS as usize {
+        usize::MAX
+    } else {
+        NODE_SIZE << shift
+    }
+}
+
+fn new_internal_node<V>() -> Node<V> {
+    Node::Internal(Arc::new(InternalNode::new()))
+}
+
+fn new_external_node<V>(value: V) -> Arc<ExternalNode<V>> {
+    let mut n = ExternalNode::new();
+    n.push(value);
+    Arc::new(n)
+}
+
+impl<V: Clone> Node<V> {
+    // keeps the first `count` elements of the subtree, a whole number of leaves,
+    // and moves the cut children to `removed`.
+    fn truncate_into(&mut self, shift: usize, count: usize, removed: &mut Vec<Node<V>>) {
+        if let Node::Internal(ref mut n) = *self {
+            let n = Arc::make_mut(n);
+            let kept = (count + (1 << shift) - 1) >> shift;
+            removed.extend(n.drain(kept..));
+            n[kept - 1].truncate_into(shift - SHIFT, count - ((kept - 1) << shift), removed);
+        }
+    }
+
+    fn make_internal_mut(&mut self) -> &mut InternalNode<V> {
+        match *self {
+            Node::Internal(ref mut n) => Arc::make_mut(n),
+            _ => panic!("expected internal node"),
+        }
+    }
+
+    fn for_each_leaf<F: FnMut(&Arc<ExternalNode<V>>)>(&self, f: &mut F) {
+        match *self {
+            Node::Internal(ref n) => {
+                for child in n.iter() {
+                    child.for_each_leaf(f);
+                }
+            }
+            Node::External(ref n) => f(n),
+            Node::Empty => {}
+        }
+    }
+
+    // returns the number of elements,
+    // `shift` is the one used to index the children
+    fn assert_valid(&self, shift: usize) -> usize {
+        match *self {
+            Node::Internal(ref n) => {
+                assert!(shift > 0, "internal node at the leaf level");
+                assert!(!n.is_empty(), "empty internal node");
+                let mut len = 0;
+                for (i, child) in n.iter().enumerate() {
+                    let child_len = child.assert_valid(shift - SHIFT);
+                    assert!(i + 1 == n.len() || child_len == 1 << shift,
+                            "partial subtree before the last one");
+                    len += child_len;
+                }
+                len
+            }
+            Node::External(ref n) => {
+                assert!(shift == 0, "leaf above the leaf level");
+                assert!(n.len() == NODE_SIZE, "partial leaf in the tree");
+                n.len()
+            }
+            Node::Empty => panic!("empty node in the tree"),
+        }
+    }
+
+    // `offset` is the index of the first element of the node
+    fn leaf_arcs_mut<'a>(&'a mut self,
+                         offset: usize,
+                         shift: usize,
+                         start: usize,
+                         end: usize,
+                         leaves: &mut Vec<&'a mut Arc<ExternalNode<V>>>) {
+        match *self {
+            Node::Internal(ref mut n) => {
+                let child_len = 1 << shift;
+                for (i, child) in Arc::make_mut(n).iter_mut().enumerate() {
+                    let child_offset = offset + i * child_len;
+                    if child_offset >= end { break; }
+                    if child_offset + child_len <= start { continue; }
+                    child.leaf_arcs_mut(child_offset, shift - SHIFT, start, end, leaves);
+                }
+            }
+            Node::External(ref mut n) => leaves.push(n),
+            Node::Empty => unreachable!(),
+        }
+    }
+
+    fn move_leaves_into(self, leaves: &mut VecDeque<Arc<ExternalNode<V>>>) {
+        match self {
+            Node::Internal(n) => {
+                match Arc::try_unwrap(n) {
+                    Ok(n) => for child in n { child.move_leaves_into(leaves); },
+                    Err(n) => for child in n.iter() { child.clone().move_leaves_into(leaves); },
+                }
+            }
+            Node::External(n) => leaves.push_back(n),
+            Node::Empty => {}
+        }
+    }
+
+    fn move_into(self, res: &mut Vec<V>) {
+        match self {
+            Node::Internal(n) => {
+                match Arc::try_unwrap(n) {
+                    Ok(n) => for child in n { child.move_into(res); },
+                    Err(n) => for child in n.iter() { child.clone().move_into(res); },
+                }
+            }
+            Node::External(n) => {
+                match Arc::try_unwrap(n) {
+                    Ok(n) => res.extend(n),
+                    Err(n) => res.extend_from_slice(&n),
+                }
+            }
+            Node::Empty => {}
+        }
+    }
+
+    fn for_each_node<F: FnMut(*const ())>(&self, f: &mut F) {
+        match *self {
+            Node::Internal(ref n) => {
+                f(Arc::as_ptr(n) as *const ());
+                for child in n.iter() {
+                    child.for_each_node(f);
+                }
+            }
+            Node::External(ref n) => f(Arc::as_ptr(n) as *const ()),
+            Node::Empty => {}
+        }
+    }
+
+    fn shared_prefix_len(a: &Node<V>, b: &Node<V>, shift: usize) -> usize {
+        match (a, b) {
+            (Node::Internal(a), Node::Internal(b)) => {
+                if Arc::ptr_eq(a, b) { return subtree_capacity(shift); }
+                let mut len = 0;
+                for (a, b) in a.iter().zip(b.iter()) {
+                    let child_len = Self::shared_prefix_len(a, b, shift - SHIFT);
+                    len += child_len;
+                    if child_len < 1 << shift { break; }
+                }
+                len
+            }
+            (Node::External(a), Node::External(b)) if Arc::ptr_eq(a, b) => {
+                NODE_SIZE
+            }
+            _ => 0,
+        }
+    }
+
+    fn into_external(self) -> Arc<ExternalNode<V>> {
+        match self {
+            Node::External(n) => n,
+            _ => panic!("expected external node"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn push_pop() {
+        let mut v = CowVec::new();
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        for i in 0..n {
+            v.push(i);
+        }
+        for i in 0..n {
+            assert!(v.pop() == Some(n - 1 - i));
+        }
+        assert!(v.pop().is_none());
+    }
+
+    #[test]
+    fn push_set_get() {
+        let mut v = CowVec::new();
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+
+        for i in 0..n {
+            v.push(i);
+        }
+        for i in 0..n {
+            if i % 2 == 0 {
+                *v.get_mut(i) = i / 2;
+            }
+        }
+        for i in 0..n {
+            let expected = if i % 2 == 0 { i / 2 } else { i };
+            assert!(v.get(i) == &expected);
+        }
+    }
+
+    #[test]
+    fn swap_remove() {
+        let mut v = CowVec::new();
+        let h = 3 * NODE_SIZE;
+        let n = 2 * h;
+
+        for i in 0..n {
+            v.push(i);
+        }
+
+        for i in 0..h {
             assert!(v.swap_remove(i) == i);
             assert!(v.get(i) == &(n - 1 - i));
         }
     }
+
+    #[test]
+    #[should_panic(expected = "swap_remove index out of bounds")]
+    fn swap_remove_empty() {
+        CowVec::<i32>::new().swap_remove(0);
+    }
+
+    #[test]
+    fn swap_remove_out_of_bounds() {
+        let mut v: CowVec<_> = (0..NODE_SIZE + 1).collect();
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            v.swap_remove(NODE_SIZE + 1)
+        }));
+        let message = res.unwrap_err();
+        assert!(message.downcast_ref::<&str>() == Some(&"swap_remove index out of bounds"));
+        // nothing was popped
+        assert!(v.iter().cloned().eq(0..NODE_SIZE + 1));
+    }
+
+    #[test]
+    fn unique() {
+        let mut v = CowVec::new();
+        for &i in &[1, 2, 1, 3, 2] {
+            v.push(i);
+        }
+
+        let u = v.unique();
+        assert!(u.iter().cloned().collect::<Vec<_>>() == vec![1, 2, 3]);
+        assert!(v.len() == 5);
+    }
+
+    #[test]
+    fn counts_in_order() {
+        let v: CowVec<_> = "abacba".chars().collect();
+        let counts = v.counts_in_order();
+        assert!(counts.iter().cloned().eq(vec![('a', 3), ('b', 2), ('c', 1)]));
+        assert!(CowVec::<char>::new().counts_in_order().is_empty());
+
+        let v: CowVec<_> = (0..1000).map(|i| i % 7).collect();
+        let counts = v.counts_in_order();
+        assert!(counts.iter().map(|p| p.0).eq(0..7));
+        assert!(counts.iter().map(|p| p.1).sum::<usize>() == 1000);
+    }
+
+    #[test]
+    fn frequencies() {
+        let mut v = CowVec::new();
+        for c in "abracadabra".chars() {
+            v.push(c);
+        }
+
+        let f = v.frequencies();
+        assert!(f.len() == 5);
+        assert!(f[&'a'] == 5);
+        assert!(f[&'b'] == 2);
+        assert!(f[&'r'] == 2);
+        assert!(f[&'c'] == 1);
+        assert!(f[&'d'] == 1);
+    }
+
+    #[test]
+    fn collect() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..NODE_SIZE / 2).collect();
+        v.extend(NODE_SIZE / 2..n);
+
+        assert!(v.len() == n);
+        for i in 0..n {
+            assert!(v.get(i) == &i);
+        }
+    }
+
+    #[test]
+    fn interleave() {
+        let a: CowVec<_> = vec![1, 3, 5].into_iter().collect();
+        let b: CowVec<_> = vec![2, 4].into_iter().collect();
+
+        let v = a.interleave(&b);
+        assert!(v.iter().cloned().collect::<Vec<_>>() == vec![1, 2, 3, 4, 5]);
+        let v = b.interleave(&a);
+        assert!(v.iter().cloned().collect::<Vec<_>>() == vec![2, 1, 4, 3, 5]);
+    }
+
+    #[test]
+    fn scan() {
+        let v: CowVec<_> = vec![1, 2, 3, 4].into_iter().collect();
+
+        let s = v.scan(0, |acc, &x| { *acc += x; *acc * 10 });
+        assert!(s.iter().cloned().collect::<Vec<_>>() == vec![10, 30, 60, 100]);
+        let s = v.prefix_sum();
+        assert!(s.iter().cloned().collect::<Vec<_>>() == vec![1, 3, 6, 10]);
+        assert!(CowVec::<i32>::new().prefix_sum().is_empty());
+    }
+
+    #[test]
+    fn intersperse() {
+        let v: CowVec<_> = vec![1, 2, 3].into();
+        assert!(v.intersperse(0).iter().cloned().eq(vec![1, 0, 2, 0, 3]));
+        let v: CowVec<_> = vec![1].into();
+        assert!(v.intersperse(0).iter().cloned().eq(vec![1]));
+        assert!(CowVec::<i32>::new().intersperse(0).is_empty());
+
+        let v: CowVec<_> = (1..100).collect();
+        let w = v.intersperse(0);
+        w.assert_valid();
+        assert!(w.len() == 2 * 99 - 1);
+        assert!(w.iter().step_by(2).cloned().eq(1..100));
+        assert!(w.iter().skip(1).step_by(2).all(|&x| x == 0));
+    }
+
+    #[test]
+    fn gather() {
+        let v: CowVec<_> = vec!['a', 'b', 'c'].into();
+        assert!(v.gather(&[2, 0, 2, 1]).iter().cloned().eq(vec!['c', 'a', 'c', 'b']));
+        assert!(v.gather(&[]).is_empty());
+
+        let v: CowVec<_> = (0..1000).collect();
+        let indices: Vec<_> = (0..1000).rev().step_by(3).collect();
+        assert!(v.gather(&indices).iter().eq(indices.iter()));
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn gather_out_of_bounds() {
+        let v: CowVec<_> = vec![1, 2, 3].into();
+        v.gather(&[0, 3]);
+    }
+
+    #[test]
+    fn scatter() {
+        let original: CowVec<_> = (0..100).collect();
+        let mut v = original.clone();
+        v.scatter(&[3, 40, 3, 41], &[1000, 2000, 3000, 4000]);
+        assert!(v.get(3) == &3000);
+        assert!(v.get(40) == &2000 && v.get(41) == &4000);
+        assert!(v.iter().enumerate().filter(|&(i, &x)| i != x).count() == 3);
+        // only the first and second leaves were copied
+        assert!(v.shared_node_count(&original) == 2);
+        assert!(original.iter().cloned().eq(0..100));
+    }
+
+    #[test]
+    #[should_panic(expected = "as many indices as values are needed")]
+    fn scatter_length_mismatch() {
+        let mut v: CowVec<_> = (0..100).collect();
+        v.scatter(&[1, 2], &[0]);
+    }
+
+    #[test]
+    fn scatter_out_of_bounds() {
+        let mut v: CowVec<_> = (0..100).collect();
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            v.scatter(&[1, 100], &[0, 0]);
+        }));
+        assert!(res.is_err());
+        assert!(v.iter().cloned().eq(0..100));
+    }
+
+    #[test]
+    fn chunk_map() {
+        let v: CowVec<_> = (0..10).map(|x| x as f64).collect();
+        let means = v.chunk_map(4, |c| c.iter().sum::<f64>() / c.len() as f64);
+        assert!(means.iter().cloned().eq(vec![1.5, 5.5, 8.5]));
+        assert!(v.chunk_map(usize::MAX, |c| c.len()).iter().cloned().eq(Some(10)));
+
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            let values: Vec<_> = (0..n).collect();
+            for &size in &[1, 7, NODE_SIZE, NODE_SIZE + 3] {
+                let sums = v.chunk_map(size, |c| c.iter().sum::<usize>());
+                assert!(sums.iter().cloned().eq(values.chunks(size).map(|c| c.iter().sum::<usize>())));
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_leaf_arc() {
+        let n = 10 * NODE_SIZE + 3;
+        let a: CowVec<_> = (0..n).collect();
+        let b = a.updated(0, 1).updated(5 * NODE_SIZE, 1);
+
+        let mut leaves = HashSet::new();
+        let mut count = 0;
+        for v in &[&a, &b] {
+            v.for_each_leaf_arc(|ptr, _| {
+                leaves.insert(ptr);
+                count += 1;
+            });
+        }
+        assert!(count == 2 * 11);
+        // the two copied leaves are the only ones not shared
+        assert!(leaves.len() == 11 + 2);
+
+        let mut lens = Vec::new();
+        a.for_each_leaf_arc(|_, leaf| lens.push(leaf.len()));
+        assert!(lens.iter().sum::<usize>() == n && lens.last() == Some(&3));
+    }
+
+    #[test]
+    fn append_fill_factor() {
+        let lens = [1, NODE_SIZE - 1, NODE_SIZE + 5, 7 * NODE_SIZE + 13, NODE_SIZE * NODE_SIZE + 3];
+        for &a in &lens {
+            for &b in &lens {
+                let mut v: CowVec<_> = (0..a).collect();
+                let mut w: CowVec<_> = (a..a + b).collect();
+                v.append(&mut w);
+                v.assert_valid();
+                assert!(v.iter().cloned().eq(0..a + b));
+                // only the tail may be partial
+                let leaves = (a + b).div_ceil(NODE_SIZE);
+                assert!(v.fill_factor() == (a + b) as f64 / (leaves * NODE_SIZE) as f64);
+                assert!(a + b < 4 * NODE_SIZE || v.fill_factor() > 0.9);
+            }
+        }
+        assert!(CowVec::<usize>::new().fill_factor() == 1.0);
+    }
+
+    #[test]
+    fn map_while() {
+        let v: CowVec<_> = vec!["1", "22", "x", "4"].into();
+        let parsed = v.map_while(|s| s.parse::<u32>().ok());
+        assert!(parsed.iter().cloned().eq(vec![1, 22]));
+
+        let v: CowVec<_> = (0..100).collect();
+        assert!(v.map_while(|&x| Some(x * 2)).iter().cloned().eq((0..100).map(|x| x * 2)));
+        assert!(v.map_while(|_| None::<usize>).is_empty());
+    }
+
+    #[test]
+    fn take_skip_while() {
+        let v: CowVec<i32> = (1..=3 * NODE_SIZE as i32).chain(vec![-1, 5, -2]).collect();
+        let positives = v.take_while(|&x| x > 0);
+        assert!(positives.iter().cloned().eq(1..=3 * NODE_SIZE as i32));
+        assert!(positives.shared_node_count(&v) == 3);
+        let rest = v.skip_while(|&x| x > 0);
+        assert!(rest.iter().cloned().eq(vec![-1, 5, -2]));
+
+        // an aligned remainder shares the leaves
+        let rest = v.skip_while(|&x| x <= NODE_SIZE as i32);
+        assert!(rest.iter().cloned().eq(v.iter().cloned().skip(NODE_SIZE)));
+        let mut shared = 0;
+        rest.for_each_leaf(&mut |a| v.for_each_leaf(&mut |b| {
+            if Arc::ptr_eq(a, b) { shared += 1; }
+        }));
+        assert!(shared == 3);
+
+        assert!(v.take_while(|_| true).len() == v.len());
+        assert!(v.skip_while(|_| true).is_empty());
+        assert!(v.take_while(|_| false).is_empty());
+        assert!(v.skip_while(|_| false).len() == v.len());
+    }
+
+    #[test]
+    fn zip_with() {
+        let a: CowVec<_> = (0..100).collect();
+        let b: CowVec<_> = (0..50).map(|i| i * 10).collect();
+
+        let v = super::zip_with(&a, &b, |x, y| x + y);
+        let expected: Vec<_> = (0..50).map(|i| i * 11).collect();
+        assert!(v.iter().cloned().collect::<Vec<_>>() == expected);
+    }
+
+    #[test]
+    fn insert() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).map(|i| 2 * i).collect();
+        let mut expected: Vec<_> = v.iter().cloned().collect();
+
+        for &i in &[n, NODE_SIZE, 1, 0, NODE_SIZE + 3] {
+            v.insert(i, 1);
+            expected.insert(i, 1);
+        }
+        assert!(v.iter().cloned().collect::<Vec<_>>() == expected);
+    }
+
+    #[test]
+    fn binary_search() {
+        let v: CowVec<_> = (0..100).map(|i| 2 * i).collect();
+        assert!(v.binary_search(&0) == Ok(0));
+        assert!(v.binary_search(&66) == Ok(33));
+        assert!(v.binary_search(&67) == Err(34));
+        assert!(v.binary_search(&500) == Err(100));
+    }
+
+    #[test]
+    fn get_unchecked() {
+        // only ever called with valid indices
+        fn checked_get(v: &CowVec<usize>, index: usize) -> Option<&usize> {
+            if index < v.len() { Some(unsafe { v.get_unchecked(index) }) } else { None }
+        }
+
+        for &n in &level_boundaries() {
+            let mut v: CowVec<_> = (0..n).collect();
+            let w = v.clone();
+            for i in 0..n {
+                assert!(checked_get(&v, i) == Some(v.get(i)));
+                unsafe { *v.get_unchecked_mut(i) *= 2; }
+            }
+            assert!(checked_get(&v, n).is_none());
+            assert!(v.iter().cloned().eq((0..n).map(|x| x * 2)));
+            assert!(w.iter().cloned().eq(0..n));
+        }
+    }
+
+    #[test]
+    fn make_unique() {
+        for &n in &level_boundaries() {
+            let original: CowVec<_> = (0..n).collect();
+            let mut v = original.clone();
+            v.make_unique();
+            match v.depth {
+                0 => assert!(v.shared_node_count(&original) == 0),
+                // the leaves below the root are still shared
+                1 => assert!(v.shared_node_count(&original) == v.leaf_aligned_chunks().count() - 1),
+                _ => {}
+            }
+
+            for x in v.make_unique().iter_mut() {
+                *x += 1;
+            }
+            assert!(v.iter().cloned().eq(1..n + 1));
+            assert!(original.iter().cloned().eq(0..n));
+            assert!(v.shared_node_count(&original) == 0);
+        }
+    }
+
+    #[test]
+    fn split_iter_at() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            for &mid in &[0, n / 3, n] {
+                let (a, b) = v.split_iter_at(mid);
+                assert!(a.len() == mid && b.len() == n - mid);
+                assert!(a.cloned().eq(0..mid));
+                assert!(b.rev().cloned().eq((mid..n).rev()));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "split index out of bounds")]
+    fn split_iter_at_out_of_bounds() {
+        let v: CowVec<_> = (0..10).collect();
+        v.split_iter_at(11);
+    }
+
+    #[test]
+    fn retain_indices() {
+        let mut v: CowVec<_> = (0..100).collect();
+        v.retain_indices(&[0, 5, 99]);
+        assert!(v.iter().cloned().eq(vec![0, 5, 99]));
+
+        let n = 4 * NODE_SIZE;
+        let original: CowVec<_> = (0..n).collect();
+        let mut v = original.clone();
+        let keep: Vec<_> = (NODE_SIZE..3 * NODE_SIZE).chain(Some(n - 1)).collect();
+        v.retain_indices(&keep);
+        v.assert_valid();
+        assert!(v.iter().cloned().eq(keep.iter().cloned()));
+        // the wholly kept leaves are reused
+        assert!(v.shared_node_count(&original) >= 2);
+        v.retain_indices(&[]);
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "indices must be sorted and unique")]
+    fn retain_indices_unsorted() {
+        let mut v: CowVec<_> = (0..100).collect();
+        v.retain_indices(&[5, 5]);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn retain_indices_out_of_bounds() {
+        let mut v: CowVec<_> = (0..100).collect();
+        v.retain_indices(&[5, 100]);
+    }
+
+    #[test]
+    fn insert_many_sorted() {
+        let mut v: CowVec<_> = (0..500).map(|x| x * 2).collect();
+        let original = v.clone();
+        // a fixed shuffle of 0..300
+        let batch: Vec<_> = (0..300).map(|i| (i * 97) % 300 + 400).collect();
+        v.insert_many_sorted(batch.clone());
+        v.assert_valid();
+        assert!(v.len() == 800);
+        assert!(v.is_sorted());
+
+        let mut expected: Vec<_> = original.iter().cloned().chain(batch).collect();
+        expected.sort();
+        assert!(v.iter().cloned().eq(expected));
+        // the 200 elements below 400 keep their leaves
+        assert!(v.shared_node_count(&original) >= 200 / NODE_SIZE);
+
+        v.insert_many_sorted(vec![]);
+        assert!(v.len() == 800);
+        let mut w = CowVec::new();
+        w.insert_many_sorted(vec![3, 1, 2]);
+        assert!(w.iter().cloned().eq(1..4));
+
+        let a: CowVec<_> = vec![1, 3, 5].into();
+        let b: CowVec<_> = vec![2, 3, 4, 6].into();
+        assert!(a.merge_sorted(&b).iter().cloned().eq(vec![1, 2, 3, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn insert_sorted_unique() {
+        let mut v = CowVec::new();
+        assert!(v.insert_sorted_unique(7));
+        assert!(!v.insert_sorted_unique(7));
+        assert!(!v.insert_sorted_unique(7));
+        assert!(v.len() == 1);
+
+        for &x in &[5, 9, 5, 1, 9] {
+            v.insert_sorted_unique(x);
+        }
+        assert!(v.iter().cloned().collect::<Vec<_>>() == vec![1, 5, 7, 9]);
+    }
+
+    #[test]
+    fn remove() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let mut expected: Vec<_> = (0..n).collect();
+
+        for &i in &[n - 1, NODE_SIZE, 0, NODE_SIZE + 3, 2 * NODE_SIZE] {
+            assert!(v.remove(i) == expected.remove(i));
+        }
+        assert!(v.iter().cloned().collect::<Vec<_>>() == expected);
+    }
+
+    #[test]
+    fn remove_sorted() {
+        let mut v: CowVec<_> = vec![1, 5, 7, 9].into_iter().collect();
+        assert!(v.remove_sorted(&7));
+        assert!(!v.remove_sorted(&8));
+        assert!(v.iter().cloned().collect::<Vec<_>>() == vec![1, 5, 9]);
+    }
+
+    #[test]
+    fn get_or_default() {
+        let v: CowVec<_> = (1..NODE_SIZE + 2).collect();
+        assert!(v.get_or_default(0) == 1);
+        assert!(v.get_or_default(NODE_SIZE) == NODE_SIZE + 1);
+        assert!(v.get_or_default(NODE_SIZE + 1) == 0);
+        assert!(v.get_or_default(2 * NODE_SIZE) == 0);
+    }
+
+    #[test]
+    fn truncate() {
+        for &n in &level_boundaries() {
+            let original: CowVec<_> = (0..n).collect();
+            for &new_len in &[0, 1, NODE_SIZE, NODE_SIZE + 1, n / 2, n - n.min(1), n, n + 1] {
+                for &shared in &[false, true] {
+                    let mut v = if shared { original.clone() } else { (0..n).collect() };
+                    v.truncate(new_len);
+                    v.assert_valid();
+                    assert!(v.iter().cloned().eq(0..new_len.min(n)));
+                    v.push(n);
+                    v.assert_valid();
+                }
+            }
+            assert!(original.iter().cloned().eq(0..n));
+        }
+
+        let n = 10 * NODE_SIZE * NODE_SIZE;
+        let v: CowVec<_> = (0..n).map(Counted).collect();
+        for &new_len in &[NODE_SIZE, NODE_SIZE + 8, n / 2 + 3] {
+            let mut w = v.clone();
+            reset_counts();
+            w.truncate(new_len);
+            assert!(clone_count() == new_len & MASK);
+            assert!(w.iter().map(|x| x.0).eq(0..new_len));
+        }
+    }
+
+    #[test]
+    fn clear_shared() {
+        let n = 10 * NODE_SIZE * NODE_SIZE;
+        let original: CowVec<_> = (0..n).map(Counted).collect();
+        let mut v = original.clone();
+        reset_counts();
+        v.clear();
+        v.assert_valid();
+        assert!(v.is_empty() && clone_count() == 0 && drop_count() == 0);
+        assert!(original.len() == n);
+        v.push(Counted(0));
+        assert!(v.len() == 1);
+    }
+
+    #[test]
+    fn truncate_with() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+
+        let mut removed = Vec::new();
+        v.truncate_with(NODE_SIZE - 3, |x| removed.push(x));
+        assert!(removed == (NODE_SIZE - 3..n).collect::<Vec<_>>());
+        assert!(v.iter().cloned().collect::<Vec<_>>() == (0..NODE_SIZE - 3).collect::<Vec<_>>());
+
+        v.truncate_with(n, |_| panic!("nothing should be removed"));
+        v.truncate(0);
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    fn read_at() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<u8> = (0..n as u8).collect();
+
+        let mut buf = [0; NODE_SIZE + 4];
+        assert!(v.read_at(NODE_SIZE - 2, &mut buf) == buf.len());
+        assert!(buf.iter().enumerate().all(|(i, &b)| b as usize == NODE_SIZE - 2 + i));
+
+        let mut buf = [0; NODE_SIZE];
+        assert!(v.read_at(n - 3, &mut buf) == 3);
+        assert!(buf[..3] == [n as u8 - 3, n as u8 - 2, n as u8 - 1]);
+        assert!(v.read_at(n, &mut buf) == 0);
+        assert!(v.read_at(n + 10, &mut buf) == 0);
+    }
+
+    #[test]
+    fn write_at() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<u8> = (0..n).map(|_| 0).collect();
+        let original = v.clone();
+
+        v.write_at(NODE_SIZE - 2, &[1; 2 * NODE_SIZE + 4]);
+        v.write_at(n - 1, &[2, 2, 2]);
+        assert!(v.len() == n + 2);
+        for i in 0..v.len() {
+            let expected = if i >= n - 1 {
+                2
+            } else if (NODE_SIZE - 2..3 * NODE_SIZE + 2).contains(&i) {
+                1
+            } else {
+                0
+            };
+            assert!(v.get(i) == &expected);
+        }
+        assert!(original.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn io_write() {
+        use std::io::Write;
+
+        let mut v = CowVec::new();
+        let mut expected = Vec::new();
+        for i in 0..NODE_SIZE {
+            writeln!(v, "line {}: {:?}", i, "some text").unwrap();
+            writeln!(expected, "line {}: {:?}", i, "some text").unwrap();
+        }
+        v.flush().unwrap();
+        assert!(v.iter().cloned().collect::<Vec<_>>() == expected);
+    }
+
+    #[test]
+    fn io_read() {
+        use std::io::Read;
+
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<u8> = (0..n as u8).collect();
+        let mut reader = CowBytesReader::new(v.clone());
+
+        let mut read = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let count = reader.read(&mut buf).unwrap();
+            if count == 0 { break; }
+            read.extend_from_slice(&buf[..count]);
+        }
+        assert!(reader.position() == n);
+        assert!(read == v.iter().cloned().collect::<Vec<_>>());
+
+        let mut reader = CowBytesReader::new(reader.into_inner());
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert!(all == read);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_leaf_slices() {
+        use rayon::prelude::*;
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            let sum: usize = v.par_leaf_slices().map(|leaf| leaf.iter().sum::<usize>()).sum();
+            assert!(sum == v.iter().sum::<usize>());
+            let lens: Vec<_> = v.par_leaf_slices().map(|leaf| leaf.len()).collect();
+            assert!(lens.iter().cloned().eq(v.leaf_spans().map(|(_, leaf)| leaf.len())));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter_mut() {
+        use rayon::prelude::*;
+        for &n in &level_boundaries() {
+            let original: CowVec<_> = (0..n).collect();
+            let mut v = original.clone();
+            v.par_iter_mut().for_each(|x| *x *= 2);
+            let mut w = original.clone();
+            for x in w.iter_mut() {
+                *x *= 2;
+            }
+            assert!(v == w);
+            assert!(original.iter().cloned().eq(0..n));
+            assert!(v.par_iter_mut().count() == n);
+            assert!(v.par_iter_mut().len() == n);
+            v.par_iter_mut().enumerate().for_each(|(i, x)| *x = i);
+            assert!(v.iter().cloned().eq(0..n));
+            let mut c = Vec::new();
+            v.par_iter_mut().map(|x| *x).collect_into_vec(&mut c);
+            assert!(c.into_iter().eq(0..n));
+            assert!(v.par_iter_mut().rev().with_max_len(1).map(|x| *x).collect::<Vec<_>>()
+                .into_iter().eq((0..n).rev()));
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter_mut_lazy() {
+        use rayon::prelude::*;
+        let n = 10 * NODE_SIZE + 3;
+        let original: CowVec<_> = (0..n).collect();
+        let mut v = original.clone();
+        v.par_iter_mut().take(NODE_SIZE + 1).for_each(|x| *x += 1);
+        assert!(v.iter().cloned().eq((1..NODE_SIZE + 2).chain(NODE_SIZE + 1..n)));
+
+        // the two leaves that were visited are copied, and the tail which
+        // is cut up front, the other leaves are still shared
+        for start in (0..n).step_by(NODE_SIZE) {
+            let shared = start > NODE_SIZE && start < n - 3;
+            assert!(Arc::ptr_eq(v.leaf_arc(start), original.leaf_arc(start)) == shared);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter_mut_split_at() {
+        use rayon::iter::plumbing::Producer;
+        let n = 5 * NODE_SIZE + 7;
+        let mut v: CowVec<_> = (0..n).collect();
+        for &a in &[0, 1, NODE_SIZE, NODE_SIZE + 3, 3 * NODE_SIZE, n - 1, n] {
+            for &b in &[0, 1, NODE_SIZE - 1, NODE_SIZE, 2 * NODE_SIZE + 5] {
+                if b > a { continue; }
+                let mut leaves: Vec<_> = v.leaf_arcs_mut(0, n).into_iter().map(Some).collect();
+                let back = Arc::make_mut(leaves.pop().unwrap().unwrap()).as_mut_slice();
+                let producer = LeavesProducer { front: &mut [], leaves: &mut leaves[..], back };
+                let (left, right) = producer.split_at(a);
+                let (left_left, left_right) = left.split_at(b);
+                assert!(left_left.into_iter().map(|x| *x).eq(0..b));
+                assert!(left_right.into_iter().map(|x| *x).eq(b..a));
+                assert!(right.into_iter().rev().map(|x| *x).eq((a..n).rev()));
+            }
+        }
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_buf() {
+        use bytes::Buf;
+
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<u8> = (0..n as u8).collect();
+        let mut reader = CowBytesReader::new(v);
+
+        assert!(reader.chunk().len() == NODE_SIZE);
+        reader.advance(3);
+        assert!(reader.remaining() == n - 3);
+        assert!(reader.chunk().len() == NODE_SIZE - 3);
+        assert!(reader.chunk()[0] == 3);
+
+        let mut expected = n - 3;
+        while reader.has_remaining() {
+            let count = reader.chunk().len().min(5);
+            reader.advance(count);
+            expected -= count;
+            assert!(reader.remaining() == expected);
+        }
+        assert!(reader.chunk().is_empty());
+        assert!(reader.position() == n);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let snapshot = v.snapshot();
+
+        for i in 0..n {
+            *v.get_mut(i) += 1;
+        }
+        v.truncate(NODE_SIZE);
+        v.push(0);
+        assert!(snapshot.len() == n);
+        assert!(snapshot.iter().cloned().eq(0..n));
+
+        v.restore(&snapshot);
+        assert!(v.iter().cloned().eq(0..n));
+        v.push(n);
+        assert!(snapshot.len() == n);
+    }
+
+    #[test]
+    fn shared_node_count() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let w = v.clone();
+        // root, 3 leaves and the tail
+        assert!(v.shared_node_count(&w) == 5);
+
+        *v.get_mut(0) = 1;
+        assert!(v.shared_node_count(&w) == 3);
+        v.push(0);
+        assert!(v.shared_node_count(&w) == 2);
+        assert!(v.shared_node_count(&CowVec::new()) == 0);
+    }
+
+    #[test]
+    fn positions() {
+        let v: CowVec<_> = (0..20).collect();
+        let p = v.positions(|x| x % 2 == 0);
+        assert!(p.iter().cloned().collect::<Vec<_>>() == (0..10).map(|i| 2 * i).collect::<Vec<_>>());
+        assert!(v.positions(|&x| x > 100).is_empty());
+    }
+
+    #[test]
+    fn rchunks_exact() {
+        let v: CowVec<_> = (0..100).collect();
+        let mut chunks = v.rchunks_exact(32);
+        assert!(chunks.len() == 3);
+
+        for end in &[100, 68, 36] {
+            let chunk = chunks.next().unwrap();
+            assert!(chunk.len() == 32);
+            assert!(chunk.cloned().eq(end - 32..*end));
+        }
+        assert!(chunks.next().is_none());
+        assert!(chunks.remainder().cloned().eq(0..4));
+
+        assert!(v.rchunks_exact(10).count() == 10);
+        assert!(v.rchunks_exact(10).remainder().len() == 0);
+        assert!(v.rchunks_exact(200).next().is_none());
+    }
+
+    #[test]
+    fn map_in_place_indexed() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).map(|_| 0).collect();
+        let w = v.clone();
+
+        v.map_in_place_indexed(|i, x| *x = i);
+        assert!(v.iter().cloned().eq(0..n));
+        assert!(w.iter().all(|&x| x == 0));
+    }
+
+    fn level_boundaries() -> Vec<usize> {
+        let mut lens = vec![0, 1];
+        for &tree in &[NODE_SIZE, NODE_SIZE * NODE_SIZE, NODE_SIZE * NODE_SIZE * NODE_SIZE] {
+            // the tree is full and the tail is empty, half full or full
+            for &tail in &[0, NODE_SIZE / 2, NODE_SIZE] {
+                lens.push(tree + tail - 1);
+                lens.push(tree + tail);
+                lens.push(tree + tail + 1);
+            }
+        }
+        lens
+    }
+
+    #[test]
+    fn level_boundaries_push_pop() {
+        for &n in &level_boundaries() {
+            let mut v = CowVec::new();
+            for i in 0..n {
+                v.push(i);
+                assert!(v.len() == i + 1);
+            }
+            for i in (0..n).step_by(7) {
+                assert!(v.get(i) == &i);
+            }
+            for i in (0..n).rev() {
+                assert!(v.pop() == Some(i));
+            }
+            assert!(v.pop().is_none());
+        }
+    }
+
+    #[test]
+    fn level_boundaries_iter() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            assert!(v.iter().cloned().eq(0..n));
+            assert!(v.iter().rev().cloned().eq((0..n).rev()));
+
+            // the cursors meet at every position of a leaf
+            for &split in &[n / 2, n.saturating_sub(NODE_SIZE), NODE_SIZE + 1] {
+                let mut it = v.iter();
+                let front: Vec<_> = it.by_ref().take(split).cloned().collect();
+                let back: Vec<_> = it.rev().cloned().collect();
+                assert!(front.len() + back.len() == n);
+                assert!(front.iter().cloned().eq(0..front.len()));
+                assert!(back.iter().cloned().eq((front.len()..n).rev()));
+            }
+
+            let mut it = v.iter();
+            let mut seen = Vec::new();
+            while let Some(&x) = it.next() {
+                seen.push(x);
+                if let Some(&x) = it.next_back() {
+                    seen.push(x);
+                }
+            }
+            seen.sort();
+            assert!(seen.iter().cloned().eq(0..n));
+        }
+    }
+
+    #[test]
+    fn append() {
+        let mut v: CowVec<_> = (0..NODE_SIZE).collect();
+        let mut w: CowVec<_> = (NODE_SIZE..4 * NODE_SIZE + 3).collect();
+        let w2 = w.clone();
+
+        v.append(&mut w);
+        assert!(w.is_empty());
+        assert!(v.iter().cloned().eq(0..4 * NODE_SIZE + 3));
+        // all the leaves of `w` are reused
+        assert!(v.shared_node_count(&w2) == 4);
+
+        let mut w: CowVec<_> = (4 * NODE_SIZE + 3..5 * NODE_SIZE).collect();
+        v.append(&mut w);
+        assert!(v.iter().cloned().eq(0..5 * NODE_SIZE));
+        v.append(&mut CowVec::new());
+        assert!(v.len() == 5 * NODE_SIZE);
+    }
+
+    #[test]
+    fn append_empties_other() {
+        for &(a, b) in &[(0, 10), (NODE_SIZE, 3 * NODE_SIZE), (5, 2 * NODE_SIZE + 7)] {
+            let mut v: CowVec<_> = (0..a).collect();
+            let mut w: CowVec<_> = (a..a + b).collect();
+            v.append(&mut w);
+
+            assert!(w.is_empty());
+            assert!(w.depth == 0);
+            assert!(w.tail.is_empty());
+            if let Node::Empty = w.root {} else { panic!("non empty root"); }
+            w.assert_valid();
+
+            for i in 0..2 * NODE_SIZE + 1 {
+                w.push(i);
+            }
+            w.assert_valid();
+            assert!(w.iter().cloned().eq(0..2 * NODE_SIZE + 1));
+            v.assert_valid();
+            assert!(v.iter().cloned().eq(0..a + b));
+        }
+    }
+
+    #[test]
+    fn prepend() {
+        let mut v: CowVec<_> = (50..200).collect();
+        let mut w: CowVec<_> = (0..50).collect();
+        v.prepend(&mut w);
+        assert!(w.is_empty());
+        v.assert_valid();
+        assert!(v.iter().cloned().eq(0..200));
+
+        // the leaves of `v` are shared when `w` ends on a leaf boundary
+        let mut v: CowVec<_> = (NODE_SIZE..4 * NODE_SIZE).collect();
+        let original = v.clone();
+        let mut w: CowVec<_> = (0..NODE_SIZE).collect();
+        v.prepend(&mut w);
+        assert!(v.iter().cloned().eq(0..4 * NODE_SIZE));
+        assert!(v.shared_node_count(&original) == 3);
+    }
+
+    #[test]
+    fn concat_all() {
+        let parts: Vec<CowVec<_>> = (0..10)
+            .map(|i| (0..i * 17).map(|x| x * i).collect())
+            .collect();
+        let refs: Vec<_> = parts.iter().collect();
+
+        let v = super::concat_all(&refs);
+        let expected: Vec<_> = parts.iter()
+            .flat_map(|p| p.iter().cloned())
+            .collect();
+        assert!(v.iter().cloned().eq(expected.into_iter()));
+        assert!(super::concat_all::<usize>(&[]).is_empty());
+    }
+
+    #[test]
+    fn depth_for_len() {
+        let depth = CowVec::<usize>::depth_for_len;
+        let n = NODE_SIZE;
+        assert!(depth(0) == 0);
+        assert!(depth(2 * n) == 0);
+        assert!(depth(2 * n + 1) == 1);
+        assert!(depth(n * n + n) == 1);
+        assert!(depth(n * n + n + 1) == 2);
+        assert!(depth(n * n * n + n) == 2);
+        assert!(depth(n * n * n + n + 1) == 3);
+
+        for &len in &level_boundaries() {
+            let mut v: CowVec<_> = (0..len).collect();
+            assert!(v.depth == depth(len));
+            v.truncate(len / 3);
+            assert!(v.depth == depth(len / 3));
+        }
+    }
+
+    #[test]
+    fn iter_mut_changed() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<_> = (0..n).collect();
+        let mut w = v.updated(NODE_SIZE + 1, 0);
+
+        let mut visited = Vec::new();
+        w.iter_mut_changed(&v, |i, x| {
+            visited.push(i);
+            *x += 1;
+        });
+        assert!(visited.into_iter().eq(NODE_SIZE..2 * NODE_SIZE));
+        assert!(w.get(NODE_SIZE + 1) == &1);
+        assert!(w.get(NODE_SIZE) == &(NODE_SIZE + 1));
+        assert!(v.get(NODE_SIZE) == &NODE_SIZE);
+
+        let mut count = 0;
+        w.iter_mut_changed(&CowVec::new(), |_, _| count += 1);
+        assert!(count == n);
+    }
+
+    thread_local! {
+        static EQ_COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[derive(Clone, Debug)]
+    struct CountEq(usize);
+
+    impl PartialEq for CountEq {
+        fn eq(&self, other: &CountEq) -> bool {
+            EQ_COUNT.with(|c| c.set(c.get() + 1));
+            self.0 == other.0
+        }
+    }
+
+    #[test]
+    fn first_difference() {
+        let n = NODE_SIZE * NODE_SIZE + 100;
+        let v: CowVec<_> = (0..n).map(CountEq).collect();
+        let w = v.updated(500, CountEq(0));
+
+        EQ_COUNT.with(|c| c.set(0));
+        assert!(v.first_difference(&w) == Some(500));
+        assert!(EQ_COUNT.with(|c| c.get()) <= NODE_SIZE);
+        assert!(v.first_difference(&v.clone()).is_none());
+
+        let mut w = v.clone();
+        w.push(CountEq(0));
+        assert!(v.first_difference(&w) == Some(n));
+        assert!(w.first_difference(&v) == Some(n));
+        w.pop();
+        *w.get_mut(n - 1) = CountEq(0);
+        assert!(v.first_difference(&w) == Some(n - 1));
+
+        let a: CowVec<_> = (0..n).map(CountEq).collect();
+        assert!(v.first_difference(&a).is_none());
+        assert!(a.first_difference(&CowVec::new()) == Some(0));
+    }
+
+    #[test]
+    fn dedup_shared_leaves() {
+        let mut v = CowVec::new();
+        v.append_n_copies(&CountEq(1), 100 * NODE_SIZE + 3);
+        v.append_n_copies(&CountEq(2), 10 * NODE_SIZE);
+        v.push(CountEq(2));
+        v.push(CountEq(1));
+
+        EQ_COUNT.with(|c| c.set(0));
+        v.dedup();
+        // the repeated leaves after the first fully dropped one are skipped
+        assert!(EQ_COUNT.with(|c| c.get()) <= 8 * NODE_SIZE);
+        v.assert_valid();
+        assert!(v.iter().map(|x| x.0).eq(vec![1, 2, 1]));
+    }
+
+    #[test]
+    fn from_fn() {
+        let v = CowVec::from_fn(100, |i| i * i);
+        assert!(v.len() == 100);
+        assert!(v.iter().cloned().eq((0..100).map(|i| i * i)));
+
+        for &n in &level_boundaries() {
+            let v = CowVec::from_fn(n, |i| i);
+            v.assert_valid();
+            assert!(v.iter().cloned().eq(0..n));
+        }
+    }
+
+    #[test]
+    fn ensure_depth() {
+        let capacity = NODE_SIZE * NODE_SIZE * NODE_SIZE;
+        for &start in &[0, 5, NODE_SIZE + 1, 3 * NODE_SIZE] {
+            let mut v: CowVec<_> = (0..start).collect();
+            v.ensure_depth(2);
+            v.assert_valid();
+            assert!(v.depth == 2);
+
+            // up to NODE_SIZE^2 leaves in the tree plus a full tail
+            for i in start..capacity + NODE_SIZE {
+                v.push(i);
+                assert!(v.depth == 2);
+            }
+            v.assert_valid();
+            v.push(0);
+            assert!(v.depth == 3);
+            v.pop();
+            assert!(v.iter().cloned().eq(0..capacity + NODE_SIZE));
+
+            v.truncate(start);
+            v.assert_valid();
+            assert!(v.iter().cloned().eq(0..start));
+        }
+
+        let mut v: CowVec<_> = (0..2 * NODE_SIZE * NODE_SIZE).collect();
+        v.ensure_depth(1);
+        assert!(v.depth == 2);
+        v.assert_valid();
+    }
+
+    #[test]
+    #[should_panic(expected = "depth too large")]
+    fn ensure_depth_overflow() {
+        let mut v: CowVec<usize> = (0..NODE_SIZE + 1).collect();
+        v.ensure_depth(usize::MAX / 2);
+    }
+
+    #[test]
+    fn into_iter_both_ends_moves_once() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).map(|i| i.to_string()).collect();
+            let ptrs: Vec<_> = v.iter().map(|s| s.as_ptr()).collect();
+
+            let mut it = v.into_iter();
+            let (mut front, mut back) = (0, n);
+            let mut seen = vec![false; n];
+            for step in 0..n {
+                let (value, i) = if step % 2 == 0 {
+                    front += 1;
+                    (it.next().unwrap(), front - 1)
+                } else {
+                    back -= 1;
+                    (it.next_back().unwrap(), back)
+                };
+                assert!(value == i.to_string());
+                // moved out of the unique leaf, not cloned
+                assert!(value.as_ptr() == ptrs[i]);
+                assert!(!seen[i]);
+                seen[i] = true;
+                assert!(it.len() == back - front);
+            }
+            assert!(it.next().is_none() && it.next_back().is_none());
+            assert!(seen.iter().all(|&s| s));
+        }
+    }
+
+    #[test]
+    fn common_prefix_len() {
+        let base: CowVec<_> = (0..500).map(CountEq).collect();
+        let mut a = base.clone();
+        let mut b = base.clone();
+        a.extend((0..100).map(CountEq));
+        b.extend((1..50).map(CountEq));
+
+        EQ_COUNT.with(|c| c.set(0));
+        assert!(a.common_prefix_len(&b) == 500);
+        // only the elements past the shared leaves are compared
+        assert!(EQ_COUNT.with(|c| c.get()) <= NODE_SIZE);
+
+        assert!(a.common_prefix_len(&a.clone()) == a.len());
+        assert!(a.common_prefix_len(&base) == 500);
+        assert!(base.common_prefix_len(&a) == 500);
+        assert!(a.common_prefix_len(&CowVec::new()) == 0);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut v = CowVec::new();
+        let mut capacities = vec![v.capacity()];
+        for i in 0..NODE_SIZE * NODE_SIZE * NODE_SIZE + NODE_SIZE + 1 {
+            v.push(i);
+            assert!(v.len() <= v.capacity());
+            if v.capacity() != *capacities.last().unwrap() {
+                // the previous capacity was just exceeded
+                assert!(v.len() == capacities.last().unwrap() + 1);
+                capacities.push(v.capacity());
+            }
+        }
+        assert!(capacities == vec![
+            NODE_SIZE + NODE_SIZE,
+            NODE_SIZE * NODE_SIZE + NODE_SIZE,
+            NODE_SIZE * NODE_SIZE * NODE_SIZE + NODE_SIZE,
+            NODE_SIZE * NODE_SIZE * NODE_SIZE * NODE_SIZE + NODE_SIZE,
+        ]);
+
+        v.ensure_depth(5);
+        assert!(v.capacity() == NODE_SIZE.pow(6) + NODE_SIZE);
+    }
+
+    #[test]
+    fn checked_sum() {
+        let v: CowVec<u32> = (0..1000).collect();
+        assert!(v.checked_sum() == Some(999 * 1000 / 2));
+        assert!(CowVec::<u8>::new().checked_sum() == Some(0));
+
+        let mut v: CowVec<u8> = iter::repeat_n(100, 100).collect();
+        assert!(v.checked_sum().is_none());
+        v.truncate(2);
+        assert!(v.checked_sum() == Some(200));
+
+        let v: CowVec<i8> = vec![-100, -28, -1].into();
+        assert!(v.checked_sum().is_none());
+    }
+
+    #[test]
+    fn leaf_spans() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            let mut end = 0;
+            for (start, leaf) in v.leaf_spans() {
+                assert!(start == end);
+                assert!(!leaf.is_empty());
+                assert!(leaf.iter().cloned().eq(start..start + leaf.len()));
+                end = start + leaf.len();
+            }
+            assert!(end == n);
+        }
+    }
+
+    #[test]
+    fn leaf_aligned_chunks() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            let chunks: Vec<_> = v.leaf_aligned_chunks().collect();
+            let tail = n - v.tail_offset();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let expected = if i + 1 == chunks.len() { tail } else { NODE_SIZE };
+                assert!(chunk.len() == expected);
+                assert!(chunk.iter().cloned().eq(i * NODE_SIZE..i * NODE_SIZE + expected));
+                // borrowed straight from the leaf
+                assert!(chunk.as_ptr() == v.leaf(i * NODE_SIZE).as_ptr());
+            }
+            assert!(chunks.iter().map(|c| c.len()).sum::<usize>() == n);
+        }
+    }
+
+    #[test]
+    fn retain_range() {
+        let mut v: CowVec<_> = (0..200).collect();
+        v.retain_range(50..100, |x| x % 2 == 0);
+        let expected: Vec<_> = (0..200).filter(|&x| !(50..100).contains(&x) || x % 2 == 0).collect();
+        assert!(v.iter().cloned().eq(expected.iter().cloned()));
+
+        v.retain_range(..10, |_| false);
+        v.retain_range(v.len() - 5.., |_| false);
+        assert!(v.iter().cloned().eq(expected[10..expected.len() - 5].iter().cloned()));
+        v.retain_range(.., |&x| x == 10);
+        assert!(v.iter().cloned().eq(Some(10)));
+
+        // the leaves before the range are kept, so are those after it when aligned
+        let original: CowVec<_> = (0..8 * NODE_SIZE).collect();
+        let mut v = original.clone();
+        v.retain_range(2 * NODE_SIZE..4 * NODE_SIZE, |x| x % NODE_SIZE < NODE_SIZE / 2);
+        v.assert_valid();
+        assert!(v.iter().cloned().eq((0..8 * NODE_SIZE).filter(|&x| {
+            !(2 * NODE_SIZE..4 * NODE_SIZE).contains(&x) || x % NODE_SIZE < NODE_SIZE / 2
+        })));
+        assert!(v.shared_node_count(&original) >= 6);
+    }
+
+    #[test]
+    fn retain_range_panic() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut v: CowVec<_> = (0..200).collect();
+        let mut calls = 0;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            v.retain_range(50..150, |_| {
+                calls += 1;
+                if calls == 20 { panic!("predicate panic"); }
+                false
+            });
+        }));
+        assert!(result.is_err());
+
+        // the leaf being processed and the ones after it are kept whole
+        v.assert_valid();
+        assert!(v.iter().cloned().eq((0..50).chain(64..200)));
+    }
+
+    #[test]
+    fn append_n_copies() {
+        let mut v: CowVec<_> = (0..5).collect();
+        v.append_n_copies(&7, 10_000);
+        assert!(v.len() == 10_005);
+        assert!(v.iter().take(5).cloned().eq(0..5));
+        assert!(v.iter().skip(5).all(|&x| x == 7));
+
+        let mut leaves = HashSet::new();
+        v.for_each_leaf(&mut |leaf| { leaves.insert(Arc::as_ptr(leaf)); });
+        assert!(leaves.len() <= 3);
+
+        v.append_n_copies(&8, 3);
+        assert!(v.len() == 10_008);
+        assert!(v.iter().skip(10_005).all(|&x| x == 8));
+        *v.get_mut(100) = 0;
+        assert!(v.iter().filter(|&&x| x == 0).count() == 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn get_out_of_bounds() {
+        // the index falls in the tail leaf but past the end
+        let v: CowVec<_> = (0..NODE_SIZE + 1).collect();
+        v.get(2 * NODE_SIZE);
+    }
+
+    #[test]
+    #[should_panic(expected = "no particle 40 in a system of 40")]
+    fn get_or() {
+        let v: CowVec<_> = (0..40).collect();
+        assert!(v.get_or(39, || unreachable!()) == &39);
+        v.get_or(40, || format!("no particle {} in a system of {}", 40, v.len()));
+    }
+
+    #[test]
+    fn clone_for_writing() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<_> = (0..n).collect();
+        let mut w = v.clone_for_writing();
+
+        assert!(Arc::strong_count(&w.tail) == 1);
+        assert!(Arc::strong_count(&v.tail) == 1);
+        // root and 3 leaves
+        assert!(w.shared_node_count(&v) == 4);
+
+        w.push(n);
+        *w.get_mut(n - 1) = 0;
+        assert!(v.iter().cloned().eq(0..n));
+        assert!(w.shared_node_count(&v) == 4);
+    }
+
+    #[test]
+    fn iter_chain() {
+        let a: CowVec<_> = (0..50).collect();
+        let b: CowVec<_> = (50..140).collect();
+
+        let mut concat = a.clone();
+        concat.append(&mut b.clone());
+        assert!(a.iter_chain(&b).eq(concat.iter()));
+        assert!(a.iter_chain(&b).rev().eq(concat.iter().rev()));
+        assert!(a.iter_chain(&CowVec::new()).eq(a.iter()));
+    }
+
+    #[test]
+    fn dedup() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let values: Vec<_> = (0..n).map(|i| i / 3 + i / NODE_SIZE).collect();
+        let mut v: CowVec<_> = values.iter().cloned().collect();
+        let mut expected = values.clone();
+
+        v.dedup();
+        expected.dedup();
+        assert!(v.iter().eq(expected.iter()));
+
+        let mut v: CowVec<_> = values.iter().cloned().collect();
+        let mut expected = values.clone();
+        v.dedup_by_key(|x| x / 4);
+        expected.dedup_by_key(|x| *x / 4);
+        assert!(v.iter().eq(expected.iter()));
+    }
+
+    #[test]
+    fn dedup_reuses_leaves() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<_> = (0..n).collect();
+        let mut w = v.clone();
+
+        w.dedup();
+        assert!(w.iter().cloned().eq(0..n));
+        // 3 leaves and the tail
+        assert!(w.shared_node_count(&v) == 4);
+
+        // only the leaves after a removal are rebuilt
+        let mut v = v.updated(2 * NODE_SIZE + 1, 2 * NODE_SIZE);
+        let w = v.clone();
+        v.dedup();
+        assert!(v.len() == n - 1);
+        assert!(v.shared_node_count(&w) == 2);
+    }
+
+    #[test]
+    fn into_boxed_slice() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let v: CowVec<_> = (0..n).map(|i| i.to_string()).collect();
+        let w = v.updated(1, String::new());
+
+        let b = v.into_boxed_slice();
+        assert!(b.len() == n);
+        assert!(b.iter().enumerate().all(|(i, s)| *s == i.to_string()));
+
+        let v: CowVec<_> = b.into_vec().into_iter().collect();
+        assert!(v.first_difference(&w) == Some(1));
+        assert!(w.into_vec()[2] == "2");
+        assert!(CowVec::<u8>::new().into_boxed_slice().is_empty());
+    }
+
+    thread_local! {
+        static CLONE_COUNT: Cell<usize> = const { Cell::new(0) };
+        static DROP_COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Counted(usize);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Counted {
+            CLONE_COUNT.with(|c| c.set(c.get() + 1));
+            Counted(self.0)
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROP_COUNT.with(|c| c.set(c.get() + 1));
+        }
+    }
+
+    fn reset_counts() {
+        CLONE_COUNT.with(|c| c.set(0));
+        DROP_COUNT.with(|c| c.set(0));
+    }
+
+    fn clone_count() -> usize {
+        CLONE_COUNT.with(|c| c.get())
+    }
+
+    fn drop_count() -> usize {
+        DROP_COUNT.with(|c| c.get())
+    }
+
+    #[test]
+    fn reverse() {
+        for &n in &level_boundaries() {
+            let mut v: CowVec<_> = (0..n).map(Counted).collect();
+            reset_counts();
+            v.reverse();
+            assert!(clone_count() == 0);
+            v.assert_valid();
+            assert!(v.iter().map(|c| c.0).eq((0..n).rev()));
+
+            // same result as swapping from both ends
+            let mut naive: CowVec<_> = (0..n).collect();
+            for i in 0..n / 2 {
+                let a = *naive.get(i);
+                let b = mem::replace(naive.get_mut(n - 1 - i), a);
+                *naive.get_mut(i) = b;
+            }
+            assert!(v.iter().map(|c| c.0).eq(naive.iter().cloned()));
+        }
+
+        // shared leaves are copied once, the original is untouched
+        let original: CowVec<_> = (0..4 * NODE_SIZE).map(Counted).collect();
+        let mut v = original.clone();
+        reset_counts();
+        v.reverse();
+        assert!(clone_count() == 4 * NODE_SIZE);
+        assert!(original.iter().map(|c| c.0).eq(0..4 * NODE_SIZE));
+        assert!(v.iter().map(|c| c.0).eq((0..4 * NODE_SIZE).rev()));
+    }
+
+    #[test]
+    fn reversed() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).map(Counted).collect();
+            reset_counts();
+            let r = v.reversed();
+            assert!(clone_count() == n);
+            r.assert_valid();
+            for i in 0..n {
+                assert!(r.get(i).0 == v.get(n - 1 - i).0);
+            }
+            assert!(v.iter().map(|c| c.0).eq(0..n));
+        }
+    }
+
+    #[test]
+    fn take_while_clones() {
+        let n = 100_000;
+        let v: CowVec<_> = (0..n).map(Counted).collect();
+        for &count in &[0, 10, NODE_SIZE, 1000, n - 1, n] {
+            reset_counts();
+            let prefix = v.take_while(|x| x.0 < count);
+            // only the elements past the last full leaf are cloned
+            assert!(clone_count() == count & MASK);
+            prefix.assert_valid();
+            assert!(prefix.iter().map(|x| x.0).eq(0..count));
+        }
+    }
+
+    #[test]
+    fn append_moves() {
+        let n = 100_000;
+        let mut v: CowVec<_> = (0..5).map(Counted).collect();
+        let mut other: CowVec<_> = (5..n).map(Counted).collect();
+        reset_counts();
+        v.append(&mut other);
+        assert!(clone_count() == 0);
+        assert!(v.iter().map(|x| x.0).eq(0..n));
+
+        let mut front: CowVec<_> = (0..5).map(Counted).collect();
+        let mut back: CowVec<_> = (5..n).map(Counted).collect();
+        reset_counts();
+        back.prepend(&mut front);
+        assert!(clone_count() == 0);
+        assert!(back.iter().map(|x| x.0).eq(0..n));
+
+        let nested: CowVec<CowVec<_>> = (0..10)
+            .map(|i| (i * 7..i * 7 + 7).map(Counted).collect())
+            .collect();
+        reset_counts();
+        let flat = nested.flatten();
+        assert!(clone_count() == 0);
+        assert!(flat.iter().map(|x| x.0).eq(0..70));
+
+        // still cloned when shared with another version
+        let mut v: CowVec<_> = (0..5).map(Counted).collect();
+        let other: CowVec<_> = (5..100).map(Counted).collect();
+        reset_counts();
+        v.append(&mut other.clone());
+        assert!(clone_count() == 95);
+        assert!(other.len() == 95);
+    }
+
+    #[test]
+    fn insert_many_sorted_moves() {
+        let n = 5 * NODE_SIZE;
+        let mut v: CowVec<_> = (0..n).map(|x| Counted(x * 2)).collect();
+        reset_counts();
+        v.insert_many_sorted((0..n).rev().map(|x| Counted(x * 2 + 1)));
+        assert!(clone_count() == 0);
+        assert!(v.iter().map(|x| x.0).eq(0..2 * n));
+    }
+
+    #[test]
+    fn retain_moves() {
+        let n = 5 * NODE_SIZE + 7;
+        let mut v: CowVec<_> = (0..n).map(Counted).collect();
+        reset_counts();
+        v.retain(|x| x.0 % 3 != 0);
+        v.dedup_by(|a, b| a.0 / 4 == b.0 / 4);
+        assert!(clone_count() == 0);
+
+        let kept = v.len();
+        let w = v.clone();
+        v.retain(|x| x.0 % 2 == 0);
+        assert!(clone_count() == v.len());
+        assert!(w.len() == kept);
+    }
+
+    #[test]
+    fn from_vec() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let values: Vec<_> = (0..n).map(Counted).collect();
+        reset_counts();
+
+        let v = CowVec::from_vec(values);
+        assert!(clone_count() == 0 && drop_count() == 0);
+        assert!(v.iter().map(|c| c.0).eq(0..n));
+        drop(v);
+        assert!(clone_count() == 0 && drop_count() == n);
+
+        let v: CowVec<_> = vec![Counted(0), Counted(1)].into();
+        assert!(v.len() == 2 && clone_count() == 0);
+    }
+
+    #[test]
+    fn all_any() {
+        let v: CowVec<usize> = CowVec::new();
+        assert!(v.all(|_| false));
+        assert!(!v.any(|_| true));
+
+        let v: CowVec<_> = (0..100).collect();
+        assert!(v.all(|&x| x < 100));
+        assert!(!v.all(|&x| x < 99));
+        assert!(v.any(|&x| x == 99));
+        assert!(!v.any(|&x| x > 99));
+
+        let mut calls = 0;
+        assert!(v.any(|&x| { calls += 1; x == 10 }));
+        assert!(calls == 11);
+    }
+
+    #[test]
+    fn assert_valid() {
+        for &n in &level_boundaries() {
+            let mut v: CowVec<_> = (0..n).collect();
+            v.assert_valid();
+            v.truncate(n / 2);
+            v.assert_valid();
+        }
+    }
+
+    #[test]
+    fn root_becomes_tail() {
+        for &n in &[NODE_SIZE + 1, 2 * NODE_SIZE, 3 * NODE_SIZE + 5] {
+            let mut v: CowVec<_> = (0..n).collect();
+            v.truncate(NODE_SIZE + 1);
+            v.assert_valid();
+            let snapshot = v.clone();
+
+            assert!(v.pop() == Some(NODE_SIZE));
+            v.assert_valid();
+            assert!(v.depth == 0);
+            assert!(v.iter().cloned().eq(0..NODE_SIZE));
+
+            v.push(42);
+            v.assert_valid();
+            v.push(43);
+            v.assert_valid();
+            assert!(v.iter().cloned().eq((0..NODE_SIZE).chain(vec![42, 43])));
+            assert!(v.pop() == Some(43));
+            assert!(v.pop() == Some(42));
+            assert!(v.pop() == Some(NODE_SIZE - 1));
+            v.assert_valid();
+            assert!(v.iter().cloned().eq(0..NODE_SIZE - 1));
+
+            snapshot.assert_valid();
+            assert!(snapshot.iter().cloned().eq(0..NODE_SIZE + 1));
+        }
+    }
+
+    #[test]
+    fn for_each_window() {
+        let values: Vec<_> = (0..200).map(|i| (i * 37) % 101).collect();
+        let v: CowVec<_> = values.iter().cloned().collect();
+
+        for &size in &[1, 3, NODE_SIZE, NODE_SIZE + 7, 200] {
+            let mut maxima = Vec::new();
+            v.for_each_window(size, |w| {
+                assert!(w.len() == size);
+                maxima.push(*w.iter().max().unwrap());
+            });
+            let expected: Vec<_> = values.windows(size)
+                .map(|w| *w.iter().max().unwrap())
+                .collect();
+            assert!(maxima == expected);
+        }
+        v.for_each_window(201, |_| panic!("no window expected"));
+    }
+
+    #[test]
+    fn split_off() {
+        for &n in &level_boundaries() {
+            for &at in &[0, n / 3, n / 2, n.saturating_sub(NODE_SIZE), n] {
+                let mut v: CowVec<_> = (0..n).collect();
+                let w = v.split_off(at);
+                v.assert_valid();
+                w.assert_valid();
+                assert!(v.iter().cloned().eq(0..at));
+                assert!(w.iter().cloned().eq(at..n));
+            }
+        }
+
+        let mut v: CowVec<_> = (0..4 * NODE_SIZE).collect();
+        let original = v.clone();
+        let w = v.split_off(NODE_SIZE);
+        assert!(w.shared_node_count(&original) == 3);
+    }
+
+    #[test]
+    fn split_off_clones() {
+        let n = 10 * NODE_SIZE * NODE_SIZE;
+        for &at in &[NODE_SIZE, NODE_SIZE + 1, n / 2 + 3] {
+            let mut v: CowVec<_> = (0..n).map(Counted).collect();
+            reset_counts();
+            let w = v.split_off(at);
+            assert!(clone_count() <= NODE_SIZE);
+            assert!(v.iter().map(|x| x.0).eq(0..at));
+            assert!(w.iter().map(|x| x.0).eq(at..n));
+        }
+
+        // the suffix of a shared vector still shares its aligned leaves
+        let original: CowVec<_> = (0..n).map(Counted).collect();
+        let mut v = original.clone();
+        reset_counts();
+        let w = v.split_off(NODE_SIZE);
+        let mut u = original.clone();
+        let x = u.split_off_back(NODE_SIZE);
+        assert!(clone_count() == 0);
+        assert!(w.len() == n - NODE_SIZE && x.len() == NODE_SIZE);
+    }
+
+    #[test]
+    fn split_off_back() {
+        let mut v: CowVec<_> = (0..200).collect();
+        let w = v.split_off_back(40);
+        assert!(v.iter().cloned().eq(0..160));
+        assert!(w.iter().cloned().eq(160..200));
+        assert!(v.split_off_back(0).is_empty());
+        assert!(v.split_off_back(160).len() == 160);
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    fn retain() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let original = v.clone();
+        v.retain(|x| x / NODE_SIZE != 1);
+        assert!(v.iter().cloned().eq((0..NODE_SIZE).chain(2 * NODE_SIZE..n)));
+        // all the remaining leaves are reused
+        assert!(v.shared_node_count(&original) == 3);
+
+        v.retain(|x| x % 3 == 0);
+        v.assert_valid();
+        assert!(v.iter().cloned().eq((0..NODE_SIZE).chain(2 * NODE_SIZE..n).filter(|x| x % 3 == 0)));
+        v.retain(|_| false);
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    fn sort_by_panic() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).map(|x| x * 37 % n).collect();
+        let mut calls = 0;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            v.sort_by(|a, b| {
+                calls += 1;
+                if calls == 20 { panic!("compare panic"); }
+                a.cmp(b)
+            });
+        }));
+        assert!(result.is_err());
+
+        v.assert_valid();
+        let mut values = v.into_vec();
+        values.sort();
+        assert!(values.into_iter().eq(0..n));
+    }
+
+    #[test]
+    fn retain_panic() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        reset_counts();
+        let mut v: CowVec<_> = (0..n).map(Counted).collect();
+        let mut calls = 0;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            v.retain(|x| {
+                calls += 1;
+                if calls == 50 { panic!("predicate panic"); }
+                x.0 % 2 == 0
+            });
+        }));
+        assert!(result.is_err());
+
+        v.assert_valid();
+        let expected: Vec<_> = (0..NODE_SIZE).filter(|x| x % 2 == 0).chain(NODE_SIZE..n).collect();
+        assert!(v.iter().map(|x| x.0).eq(expected.into_iter()));
+        drop(v);
+        assert!(drop_count() == n + clone_count());
+    }
+
+    #[test]
+    fn truncate_panic_on_drop() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        thread_local! {
+            static DROPS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        struct PanicDrop(usize, bool);
+
+        impl Clone for PanicDrop {
+            fn clone(&self) -> PanicDrop {
+                PanicDrop(self.0, false)
+            }
+        }
+
+        impl Drop for PanicDrop {
+            fn drop(&mut self) {
+                DROPS.with(|c| c.set(c.get() + 1));
+                if self.1 && !::std::thread::panicking() { panic!("drop panic"); }
+            }
+        }
+
+        let n = 3 * NODE_SIZE + 5;
+        for &(new_len, panicking) in &[(NODE_SIZE + 3, 2 * NODE_SIZE), (0, n - 1), (0, 0)] {
+            DROPS.with(|c| c.set(0));
+            let mut v: CowVec<_> = (0..n).map(|i| PanicDrop(i, i == panicking)).collect();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                if new_len == 0 { v.clear() } else { v.truncate(new_len) }
+            }));
+            assert!(result.is_err());
+
+            // the vector already had its new length when the drops started
+            v.assert_valid();
+            assert!(v.len() == new_len);
+            assert!(v.iter().map(|x| x.0).eq(0..new_len));
+            assert!(DROPS.with(|c| c.get()) == n - new_len);
+            drop(v);
+            assert!(DROPS.with(|c| c.get()) == n);
+        }
+    }
+
+    #[test]
+    fn iter_mut() {
+        for &n in &level_boundaries() {
+            let mut v: CowVec<_> = (0..n).collect();
+            let w = v.clone();
+            assert!(v.iter_mut().len() == n);
+            for x in &mut v {
+                *x *= 2;
+            }
+            assert!(v.iter().cloned().eq((0..n).map(|x| x * 2)));
+            assert!(v.iter_mut().rev().map(|x| *x).eq((0..n).rev().map(|x| x * 2)));
+            assert!(w.iter().cloned().eq(0..n));
+
+            let mut it = v.iter_mut();
+            let mut count = 0;
+            while let Some(_) = it.next() {
+                count += 1;
+                if it.next_back().is_some() { count += 1; }
+            }
+            assert!(count == n);
+        }
+    }
+
+    #[test]
+    fn get_range_mut() {
+        let n = 4 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let original = v.clone();
+
+        {
+            let mut range = v.get_range_mut(NODE_SIZE..3 * NODE_SIZE);
+            assert!(range.len() == 2 * NODE_SIZE);
+            for x in range.iter_mut() {
+                *x *= 2;
+            }
+            *range.get_mut(1) += 1;
+            assert!(range.get(1) == &(2 * NODE_SIZE + 3));
+        }
+        let expected = (0..n).map(|i| match i {
+            i if i == NODE_SIZE + 1 => 2 * i + 1,
+            i if (NODE_SIZE..3 * NODE_SIZE).contains(&i) => 2 * i,
+            i => i,
+        });
+        assert!(v.iter().cloned().eq(expected));
+        // first leaf, fourth leaf and tail
+        assert!(v.shared_node_count(&original) == 3);
+
+        assert!(v.get_range_mut(5..5).iter_mut().next().is_none());
+        let mut range = v.get_range_mut(NODE_SIZE - 1..=4 * NODE_SIZE);
+        assert!(range.iter_mut().count() == 3 * NODE_SIZE + 2);
+    }
+
+    #[test]
+    fn leaf_slices_mut() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<i64> = (0..n as i64).collect();
+        let w = v.clone();
+
+        let mut lens = Vec::new();
+        for leaf in v.leaf_slices_mut() {
+            lens.push(leaf.len());
+            for x in leaf.iter_mut() {
+                *x = -*x;
+            }
+        }
+        assert!(lens == vec![NODE_SIZE, NODE_SIZE, NODE_SIZE, NODE_SIZE / 2]);
+        assert!(v.iter().cloned().eq((0..n as i64).map(|x| -x)));
+        assert!(w.iter().cloned().eq(0..n as i64));
+        assert!(v.shared_node_count(&w) == 0);
+    }
+
+    #[test]
+    fn leaf_slices_mut_lazy() {
+        let n = 10 * NODE_SIZE + 3;
+        let original: CowVec<_> = (0..n).map(Counted).collect();
+        let mut v = original.clone();
+        reset_counts();
+        v.leaf_slices_mut().next().unwrap()[0].0 = n;
+        assert!(clone_count() == NODE_SIZE);
+        v.iter_mut().nth(NODE_SIZE + 1).unwrap().0 = n;
+        assert!(clone_count() == 2 * NODE_SIZE);
+        v.iter_mut().next_back().unwrap().0 = n;
+        assert!(clone_count() == 2 * NODE_SIZE + 3);
+
+        // the leaves that were not visited are still shared
+        for start in (2 * NODE_SIZE..n - 3).step_by(NODE_SIZE) {
+            assert!(Arc::ptr_eq(v.leaf_arc(start), original.leaf_arc(start)));
+        }
+        assert!(!Arc::ptr_eq(v.leaf_arc(NODE_SIZE), original.leaf_arc(NODE_SIZE)));
+    }
+
+    #[test]
+    fn iter_range_mut() {
+        for &n in &level_boundaries() {
+            let mut v: CowVec<_> = (0..n).collect();
+            let bounds = [0, 1, NODE_SIZE - 1, NODE_SIZE, NODE_SIZE + 1, n / 2, n.max(1) - 1, n];
+            for &start in bounds.iter().filter(|&&b| b <= n) {
+                for &end in bounds.iter().filter(|&&b| start <= b && b <= n) {
+                    assert!(v.iter_range_mut(start, end).map(|x| *x).eq(start..end));
+                    assert!(v.iter_range_mut(start, end).rev().map(|x| *x).eq((start..end).rev()));
+                    assert!(v.iter_range_mut(start, end).len() == end - start);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sort() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).map(|i| (i * 37) % n).collect();
+        assert!(!v.is_sorted());
+        v.sort();
+        assert!(v.is_sorted());
+        assert!(v.iter().cloned().eq(0..n));
+
+        v.sort_by(|a, b| b.cmp(a));
+        assert!(v.iter().cloned().eq((0..n).rev()));
+        assert!(CowVec::<usize>::new().is_sorted());
+    }
+
+    #[test]
+    fn sort_sorted_reuses_leaves() {
+        let n = 3 * NODE_SIZE + NODE_SIZE / 2;
+        let mut v: CowVec<_> = (0..n).collect();
+        let original = v.clone();
+        v.sort();
+        assert!(v.shared_node_count(&original) == 5);
+    }
+
+    #[test]
+    fn partition_point() {
+        let v: CowVec<_> = (0..100).map(|i| i >= 70).collect();
+        assert!(v.partition_point(|&b| !b) == 70);
+
+        let v: CowVec<_> = (0..100).collect();
+        assert!(v.partition_point(|&x| x < 33) == 33);
+        assert!(v.partition_point(|_| true) == 100);
+        assert!(v.partition_point(|_| false) == 0);
+        assert!(CowVec::<usize>::new().partition_point(|_| true) == 0);
+    }
+
+    #[test]
+    fn into_iter() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).collect();
+            let w = v.clone();
+            assert!(v.clone().into_iter().len() == n);
+            // the leaves are shared with `w` here, and unique after
+            assert!(v.into_iter().rev().eq((0..n).rev()));
+            assert!(w.into_iter().eq(0..n));
+        }
+    }
+
+    #[test]
+    fn fused_iterators() {
+        let n = NODE_SIZE + 3;
+        let mut v: CowVec<_> = (0..n).collect();
+
+        let mut it = v.iter();
+        assert!(it.by_ref().count() == n);
+        for _ in 0..3 {
+            assert!(it.next().is_none());
+            assert!(it.next_back().is_none());
+        }
+
+        let mut it = v.iter_mut();
+        assert!(it.by_ref().rev().count() == n);
+        for _ in 0..3 {
+            assert!(it.next().is_none());
+            assert!(it.next_back().is_none());
+        }
+
+        let mut it = v.clone().into_iter();
+        assert!(it.by_ref().count() == n);
+        for _ in 0..3 {
+            assert!(it.next().is_none());
+            assert!(it.next_back().is_none());
+        }
+        assert!(it.len() == 0);
+
+        let mut it = CowVec::<usize>::new().into_iter();
+        assert!(it.next().is_none() && it.next_back().is_none());
+    }
+
+    // a tree of maximal depth with a single path,
+    // its length only makes sense for the first leaf.
+    fn tallest_tree() -> CowVec<usize> {
+        let depth = (usize::BITS as usize - 1) / SHIFT;
+        let mut root = Node::External(Arc::new((0..NODE_SIZE).collect()));
+        for _ in 0..depth {
+            let mut n = InternalNode::new();
+            n.push(root);
+            root = Node::Internal(Arc::new(n));
+        }
+        CowVec {
+            root,
+            depth,
+            tail: new_external_node(0),
+            len: usize::MAX,
+        }
+    }
+
+    #[test]
+    fn deep_shifts() {
+        let mut v = tallest_tree();
+        assert!(v.root_shift() + SHIFT >= usize::BITS as usize);
+        for i in 0..NODE_SIZE {
+            assert!(v.get(i) == &i);
+            assert!(v.leaf(i).len() == NODE_SIZE);
+        }
+        *v.get_mut(3) = 42;
+        assert!(v.get(3) == &42);
+
+        let w = v.clone();
+        assert!(v.shared_prefix_len(&w) == usize::MAX);
+        assert!(subtree_capacity(v.root_shift()) == usize::MAX);
+        assert!(CowVec::<usize>::depth_for_len(usize::MAX) == v.depth);
+    }
+
+    #[test]
+    fn swap_remove_moves() {
+        let n = 3 * NODE_SIZE + 1;
+        let mut v: CowVec<_> = (0..n).map(Counted).collect();
+        reset_counts();
+
+        // the last element is alone in the tail
+        assert!(v.swap_remove(5).0 == 5);
+        assert!(v.get(5).0 == n - 1);
+        for i in 6..6 + NODE_SIZE {
+            assert!(v.swap_remove(i).0 == i);
+        }
+        assert!(clone_count() == 0);
+        assert!(drop_count() == NODE_SIZE + 1);
+
+        let w = v.clone();
+        v.swap_remove(0);
+        assert!(clone_count() > 0);
+        assert!(w.len() == v.len() + 1);
+    }
+
+    #[test]
+    fn try_into_array() {
+        let v: CowVec<_> = (0..NODE_SIZE + 4).collect();
+        let v = match v.try_into_array::<4>() {
+            Ok(_) => panic!("the length is not 4"),
+            Err(v) => v,
+        };
+        assert!(v.len() == NODE_SIZE + 4);
+
+        let a: [usize; NODE_SIZE + 4] = v.try_into_array().unwrap();
+        assert!(a.iter().cloned().eq(0..NODE_SIZE + 4));
+        let empty: [u8; 0] = CowVec::new().try_into_array().unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn min_max_by() {
+        let v: CowVec<i32> = (0..100).map(|i| (i * 37) % 101).collect();
+        let target = 50;
+        let distance = |a: &i32, b: &i32| (a - target).abs().cmp(&(b - target).abs());
+        assert!(v.min_by(distance) == Some(&50));
+        assert!(v.max_by(distance) == Some(&0));
+
+        // 49 and 51 are equally close, the first one wins
+        let v: CowVec<_> = vec![10, 51, 49, 90, 10].into_iter().collect();
+        assert!(v.min_by(distance) == Some(&51));
+        let pos = |x: &i32| v.iter().position(|y| std::ptr::eq(x, y));
+        assert!(pos(v.max_by(distance).unwrap()) == Some(0));
+        let same = |_: &i32, _: &i32| Ordering::Equal;
+        assert!(pos(v.min_by(same).unwrap()) == Some(0));
+        assert!(pos(v.max_by(same).unwrap()) == Some(0));
+        assert!(CowVec::<i32>::new().min_by(distance).is_none());
+    }
+
+    #[test]
+    fn unzip() {
+        let v: CowVec<_> = vec![(1, 'a'), (2, 'b')].into();
+        let (a, b) = v.unzip();
+        assert!(a.iter().cloned().eq(vec![1, 2]));
+        assert!(b.iter().cloned().eq(vec!['a', 'b']));
+
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).map(|i| (i, i * 2)).collect();
+            let (a, b) = v.unzip();
+            a.assert_valid();
+            b.assert_valid();
+            assert!(a.iter().cloned().eq(0..n));
+            assert!(b.iter().cloned().eq((0..n).map(|i| i * 2)));
+        }
+    }
+
+    #[test]
+    fn flatten() {
+        let inner: Vec<CowVec<_>> = (0..10)
+            .map(|i| (0..i * 13).map(|x| x + i).collect())
+            .collect();
+        let expected: Vec<_> = inner.iter().flat_map(|v| v.iter().cloned()).collect();
+        let v: CowVec<_> = inner.into_iter().collect();
+        assert!(v.flatten().iter().cloned().eq(expected.into_iter()));
+
+        let inner: Vec<CowVec<_>> = (0..4)
+            .map(|i| (0..2 * NODE_SIZE).map(|x| x * i).collect())
+            .collect();
+        let v: CowVec<_> = inner.iter().cloned().collect();
+        let flat = v.flatten();
+        assert!(flat.len() == 8 * NODE_SIZE);
+        for w in &inner {
+            assert!(flat.shared_node_count(w) == 2);
+        }
+    }
+
+    #[test]
+    fn count_true() {
+        let v: CowVec<_> = (0..200).map(|i| i % 3 == 0 || i % 5 == 0).collect();
+        assert!(v.count_true() == (0..200).filter(|i| i % 3 == 0 || i % 5 == 0).count());
+        assert!(CowVec::<bool>::new().count_true() == 0);
+    }
+
+    #[test]
+    fn toggle_range() {
+        let n = 4 * NODE_SIZE + 3;
+        let mut v: CowVec<_> = (0..n).map(|i| i % 3 == 0).collect();
+        let original = v.clone();
+
+        v.toggle_range(NODE_SIZE + 5..2 * NODE_SIZE + 1);
+        assert!(v.iter().enumerate().all(|(i, &b)| {
+            b == ((i % 3 == 0) != (NODE_SIZE + 5..2 * NODE_SIZE + 1).contains(&i))
+        }));
+        // the first, fourth and fifth leaves
+        assert!(v.shared_node_count(&original) == 3);
+
+        v.toggle_range(NODE_SIZE + 5..2 * NODE_SIZE + 1);
+        assert!(v.first_difference(&original).is_none());
+        v.toggle_range(..);
+        assert!(v.count_true() == n - original.count_true());
+    }
+
+    #[test]
+    fn extend_ref() {
+        let a: CowVec<_> = (0..100).collect();
+        let mut b: CowVec<_> = (0..10).collect();
+        b.extend(a.iter());
+        b.extend(&a);
+        assert!(b.len() == 210);
+        assert!(b.iter().cloned().eq((0..10).chain(0..100).chain(0..100)));
+        b.assert_valid();
+    }
+
+    #[test]
+    fn split_off_prefix() {
+        let n = 5 * NODE_SIZE + 7;
+        for &at in &[0, 1, NODE_SIZE, 2 * NODE_SIZE + 3, n] {
+            let original: CowVec<_> = (0..n).collect();
+            let mut v = original.clone();
+            let prefix = v.split_off_prefix(at);
+            prefix.assert_valid();
+            v.assert_valid();
+            assert!(prefix.iter().cloned().eq(0..at));
+            assert!(v.iter().cloned().eq(at..n));
+            if at >= NODE_SIZE {
+                assert!(prefix.shared_node_count(&original) > 0);
+            }
+        }
+
+        let original: CowVec<_> = (0..n).collect();
+        let mut v = original.clone();
+        v.split_off_prefix(2 * NODE_SIZE);
+        // the aligned suffix shares every full leaf of the original
+        let mut shared = 0;
+        v.for_each_leaf(&mut |a| original.for_each_leaf(&mut |b| {
+            if Arc::ptr_eq(a, b) { shared += 1; }
+        }));
+        assert!(shared == 4);
+    }
+
+    #[test]
+    fn downgrade() {
+        for &n in &[0, 5, NODE_SIZE + 1, NODE_SIZE * NODE_SIZE + 40] {
+            let v: CowVec<_> = (0..n).collect();
+            let weak = v.downgrade();
+            let upgraded = weak.upgrade().unwrap();
+            assert!(upgraded.iter().cloned().eq(0..n));
+            assert!(upgraded.shared_node_count(&v) > 0);
+
+            drop(upgraded);
+            drop(v);
+            assert!(weak.upgrade().is_none());
+        }
+
+        // a weak version observes the nodes, not a particular handle
+        let v: CowVec<_> = (0..100).collect();
+        let weak = v.downgrade();
+        let other = v.clone();
+        drop(v);
+        assert!(weak.upgrade().unwrap().iter().cloned().eq(0..100));
+        drop(other);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn concat_split_valid() {
+        let lens = [0, 1, NODE_SIZE - 1, NODE_SIZE, 3 * NODE_SIZE + 5, NODE_SIZE * NODE_SIZE + 1];
+        for &a in &lens {
+            for &b in &lens {
+                let mut v: CowVec<_> = (0..a).collect();
+                let mut w: CowVec<_> = (a..a + b).collect();
+                v.append(&mut w);
+                v.assert_valid();
+                assert!(v.iter().cloned().eq(0..a + b));
+
+                for &at in &[0, a, (a + b) / 2, a + b] {
+                    let mut prefix = v.clone();
+                    let mut suffix = prefix.split_off(at);
+                    prefix.assert_valid();
+                    suffix.assert_valid();
+
+                    suffix.prepend(&mut prefix);
+                    suffix.assert_valid();
+                    assert!(suffix.iter().cloned().eq(0..a + b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partition_off() {
+        let mut v: CowVec<_> = (0..100).collect();
+        let odds = v.partition_off(|&x| x % 2 == 0);
+        v.assert_valid();
+        odds.assert_valid();
+        assert!(v.iter().cloned().eq((0..100).step_by(2)));
+        assert!(odds.iter().cloned().eq((1..100).step_by(2)));
+
+        let mut v: CowVec<_> = (0..100).collect();
+        assert!(v.partition_off(|_| true).is_empty());
+        assert!(v.len() == 100);
+        let all = v.partition_off(|_| false);
+        assert!(v.is_empty());
+        assert!(all.iter().cloned().eq(0..100));
+    }
+
+    #[test]
+    fn equal_range() {
+        let v: CowVec<_> = (0..100).flat_map(|i| iter::repeat_n(i, i % 4)).collect();
+        let start = (0..10).map(|i| i % 4).sum::<usize>();
+        assert!(v.equal_range(&10) == (start..start + 2));
+        assert!(v.equal_range(&11).len() == 3);
+        assert!(v.iter_range(v.equal_range(&99).start, v.len()).all(|&x| x == 99));
+        // absent values give the insertion point
+        assert!(v.equal_range(&8) == (start - 1..start - 1));
+        assert!(v.equal_range(&1000) == (v.len()..v.len()));
+        assert!(CowVec::<i32>::new().equal_range(&0) == (0..0));
+    }
+
+    #[test]
+    fn append_iter() {
+        for &start in &[0, 10, NODE_SIZE + 1, 300] {
+            let n = NODE_SIZE * NODE_SIZE + 100;
+            let mut v: CowVec<_> = (0..start).collect();
+            v.append_iter(start..start + n);
+            assert!(v.depth == CowVec::<usize>::depth_for_len(start + n));
+            v.assert_valid();
+            assert!(v.iter().cloned().eq(0..start + n));
+
+            // no exact length to rely on
+            let mut w: CowVec<_> = (0..start).collect();
+            w.append_iter((start..start + n).filter(|_| true));
+            w.assert_valid();
+            assert!(w.iter().cloned().eq(0..start + n));
+        }
+    }
+
+    #[test]
+    fn get_pair_mut_or_clone() {
+        let mut v: CowVec<_> = (0..100).collect();
+        let (a, b) = v.get_pair_mut_or_clone(3, 70);
+        *b += a;
+        assert!(v.get(70) == &73);
+
+        let (a, b) = v.get_pair_mut_or_clone(70, 70);
+        *b *= a;
+        assert!(v.get(70) == &(73 * 73));
+        assert!(v.iter().enumerate().all(|(i, &x)| i == 70 || x == i));
+    }
+
+    #[test]
+    fn from_iter_size_hint() {
+        // reports whether its exact size was asked for
+        struct Hinted<'a>(::std::ops::Range<usize>, &'a Cell<bool>);
+
+        impl<'a> Iterator for Hinted<'a> {
+            type Item = usize;
+
+            fn next(&mut self) -> Option<usize> {
+                self.0.next()
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.1.set(true);
+                self.0.size_hint()
+            }
+        }
+
+        let n = 1_000_000;
+        let asked = Cell::new(false);
+        let v: CowVec<_> = Hinted(0..n, &asked).map(|x| x + 1).enumerate().collect();
+        assert!(asked.get());
+        assert!(v.depth == CowVec::<usize>::depth_for_len(n));
+        assert!(v.iter().all(|&(i, x)| x == i + 1));
+        v.assert_valid();
+    }
+
+    #[test]
+    fn normalize() {
+        let mut v: CowVec<_> = (0..NODE_SIZE * NODE_SIZE + 2 * NODE_SIZE).collect();
+        assert!(v.depth == 2);
+        v.truncate(NODE_SIZE + 5);
+        v.normalize();
+        assert!(v.depth == 0);
+        v.assert_valid();
+
+        v.ensure_depth(3);
+        v.retain(|&x| x != 0);
+        v.ensure_depth(3);
+        v.normalize();
+        assert!(v.depth == 0);
+        v.assert_valid();
+        assert!(v.iter().cloned().eq(1..NODE_SIZE + 5));
+
+        let mut v: CowVec<usize> = CowVec::new();
+        v.ensure_depth(2);
+        v.normalize();
+        assert!(v.depth == 0);
+        v.extend(0..100);
+        v.assert_valid();
+    }
+
+    #[test]
+    fn eq_hash() {
+        use std::collections::hash_map::DefaultHasher;
+
+        fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+            let mut state = DefaultHasher::new();
+            value.hash(&mut state);
+            state.finish()
+        }
+
+        for &n in &level_boundaries() {
+            let values: Vec<_> = (0..n).collect();
+            let v = CowVec::from_vec(values.clone());
+            let w: CowVec<_> = (0..n).collect();
+            assert!(v == w);
+            assert!(hash(&v) == hash(&w));
+            assert!(hash(&v) == hash(&values[..]));
+
+            if n > 0 {
+                assert!(v != w.updated(n / 2, n));
+                assert!(v != w.updated(n - 1, n));
+                let mut u = w.clone();
+                u.pop();
+                assert!(v != u);
+            }
+        }
+
+        // the shared leaves are not compared
+        let v: CowVec<_> = (0..NODE_SIZE * NODE_SIZE).map(CountEq).collect();
+        let w = v.updated(NODE_SIZE * 3, CountEq(0));
+        EQ_COUNT.with(|c| c.set(0));
+        assert!(v != w);
+        assert!(EQ_COUNT.with(|c| c.get()) <= NODE_SIZE);
+        assert!(v == v.clone());
+    }
+
+    #[test]
+    fn exact_size_hints() {
+        fn check<I: DoubleEndedIterator>(mut it: I, n: usize) {
+            let mut remaining = n;
+            while remaining > 0 {
+                assert!(it.size_hint() == (remaining, Some(remaining)));
+                if remaining.is_multiple_of(3) { it.next_back() } else { it.next() }.unwrap();
+                remaining -= 1;
+            }
+            assert!(it.size_hint() == (0, Some(0)));
+            assert!(it.next().is_none());
+        }
+
+        for &n in &level_boundaries() {
+            let mut v: CowVec<_> = (0..n).collect();
+            check(v.iter(), n);
+            check(v.iter_mut(), n);
+            check(v.clone().into_iter(), n);
+            // leaves shared with another version are cloned instead
+            let w = v.clone();
+            check(v.into_iter(), n);
+            assert!(w.len() == n);
+        }
+    }
+
+    #[test]
+    fn from_into_iter() {
+        for &n in &level_boundaries() {
+            let v: CowVec<_> = (0..n).map(Counted).collect();
+            let leaves: Vec<_> = v.leaf_aligned_chunks().map(|leaf| leaf.as_ptr()).collect();
+            reset_counts();
+            let w = CowVec::from(v.into_iter());
+            assert!(clone_count() == 0);
+            w.assert_valid();
+            assert!(w.iter().map(|c| c.0).eq(0..n));
+            assert!(w.leaf_aligned_chunks().map(|leaf| leaf.as_ptr()).eq(leaves));
+
+            // the source keeps its version, the leaves are shared with it
+            let v: CowVec<_> = (0..n).collect();
+            let w = CowVec::from(v.clone().into_iter());
+            assert!(w.shared_node_count(&v) >= v.leaf_aligned_chunks().count());
+        }
+
+        // partially consumed from both ends
+        let v: CowVec<_> = (0..10 * NODE_SIZE + 3).collect();
+        let mut it = v.into_iter();
+        it.by_ref().take(NODE_SIZE).count();
+        it.next_back();
+        let w = CowVec::from(it);
+        w.assert_valid();
+        assert!(w.iter().cloned().eq(NODE_SIZE..10 * NODE_SIZE + 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "range start overflows usize")]
+    fn retain_range_start_overflow() {
+        let mut v: CowVec<_> = (0..10).collect();
+        v.retain_range((Bound::Excluded(usize::MAX), Bound::Unbounded), |_| false);
+    }
+
+    #[test]
+    #[should_panic(expected = "range end overflows usize")]
+    fn range_end_overflow() {
+        let mut v: CowVec<_> = (0..10).map(|_| false).collect();
+        v.toggle_range(0..=usize::MAX);
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_off_back_too_many() {
+        let mut v: CowVec<_> = (0..10).collect();
+        v.split_off_back(11);
+    }
 }